@@ -1,11 +1,25 @@
 use std::{
+    collections::HashMap,
     fmt,
     net::IpAddr,
     ops::DerefMut,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::Duration,
 };
 
+use argon2::{
+    Argon2,
+    PasswordHasher,
+    PasswordVerifier,
+};
 use chrono::{
-    serde::ts_nanoseconds,
+    serde::{
+        ts_nanoseconds,
+        ts_nanoseconds_option,
+    },
     DateTime,
     Utc,
 };
@@ -19,11 +33,14 @@ use couch_rs::{
         DocumentCollection,
         TypedCouchDocument,
     },
+    error::CouchError,
     types::{
         document::DocumentId,
+        query::QueryParams,
         view::{
             CouchFunc,
             CouchViews,
+            ViewCollection,
         },
     },
     CouchDocument,
@@ -35,16 +52,31 @@ use log::{
     trace,
     warn,
 };
+use password_hash::{
+    rand_core::{
+        OsRng,
+        RngCore,
+    },
+    PasswordHash,
+    SaltString,
+};
 use redis::{
-    aio::{
-        MultiplexedConnection,
-        PubSub,
+    aio::MultiplexedConnection,
+    streams::{
+        StreamAutoClaimOptions,
+        StreamAutoClaimReply,
+        StreamClaimOptions,
+        StreamId,
+        StreamMaxlen,
+        StreamRangeReply,
+        StreamReadOptions,
+        StreamReadReply,
     },
     AsyncCommands,
-    Client,
     Cmd,
     FromRedisValue as RV,
     RedisError,
+    Script,
     ToRedisArgs,
 };
 use serde::{
@@ -55,6 +87,15 @@ use serde_json::{
     Map,
     Value,
 };
+use sha2::{
+    Digest,
+    Sha256,
+};
+use totp_rs::{
+    Algorithm,
+    Secret,
+    TOTP,
+};
 use uuid::{
     timestamp::context::ThreadLocalContext,
     Uuid,
@@ -68,6 +109,35 @@ pub struct PostBody
     #[serde(with = "ts_nanoseconds")]
     pub time: DateTime<Utc>,
     pub email: String,
+    #[serde(default)]
+    pub attachment: Option<Attachment>,
+}
+
+/* a file as stored by the worker. files are named after their content hash;
+ * `file` and `thumb` are relative to the src/ and thumb/ trees of the file
+ * directory.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Attachment
+{
+    pub filename: String,
+    pub hash: String,
+    pub size: u64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub mime: String,
+    pub file: String,
+    pub thumb: Option<String>,
+}
+
+/* a file that web has received but the worker has yet to store. it waits in
+ * the staging directory under the id of the post request.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Upload
+{
+    pub filename: String,
+    pub content_type: String,
 }
 
 pub fn _thread() -> String
@@ -78,6 +148,22 @@ pub fn _comment() -> String
 {
     "comment".to_string()
 }
+pub fn _ban() -> String
+{
+    "ban".to_string()
+}
+pub fn _filter() -> String
+{
+    "filter".to_string()
+}
+pub fn _board() -> String
+{
+    "board".to_string()
+}
+pub fn _staff() -> String
+{
+    "staff".to_string()
+}
 
 #[derive(Serialize, Deserialize, CouchDocument, Clone)]
 pub struct Thread
@@ -92,11 +178,27 @@ pub struct Thread
     pub board_code: String,
     #[serde(rename = "tid")]
     pub thread_num: i32,
+    #[serde(default)]
+    pub subject: String,
     pub body: PostBody,
     #[serde(with = "ts_nanoseconds")]
     pub bump_time: DateTime<Utc>,
     pub archived: bool,
     pub pinned: bool,
+    #[serde(default)]
+    pub priority: i32, /* pinned threads with higher priorities go first */
+    #[serde(default)]
+    pub locked: bool,
+    #[serde(default)]
+    pub autosage: bool, /* past the bump limit, replies no longer bump it */
+    #[serde(default)]
+    pub flagged: Vec<String>, /* ids of the filters that flagged it */
+    #[serde(default)]
+    pub hidden: bool, /* deleted by staff, kept for the record */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>, /* only in the main db */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delete_hash: Option<String>, /* the poster's, main db only */
     pub comments: Option<Vec<Comment>>, /* used in the listing db, ignored
                                          * otherwise */
 }
@@ -117,6 +219,122 @@ pub struct Comment
     pub parent_thread_id: DocumentId,
     pub body: PostBody,
     pub archived: bool,
+    #[serde(default)]
+    pub flagged: Vec<String>,
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delete_hash: Option<String>,
+}
+
+/* a ban on posting from an address or CIDR range, on one board or, without
+ * a board code, on all of them. bans without an expiry are permanent.
+ */
+#[derive(Serialize, Deserialize, CouchDocument, Clone)]
+pub struct Ban
+{
+    #[serde(default = "_ban")]
+    pub t: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub _id: DocumentId,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub _rev: String,
+    pub range: String,
+    #[serde(rename = "bc")]
+    pub board_code: Option<String>,
+    pub reason: String,
+    pub staff: String,
+    #[serde(with = "ts_nanoseconds")]
+    pub time: DateTime<Utc>,
+    #[serde(with = "ts_nanoseconds_option", default)]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+/* state kept for a board as a whole. boards have no document until
+ * something is set on them.
+ */
+#[derive(Serialize, Deserialize, CouchDocument, Clone)]
+pub struct Board
+{
+    #[serde(default = "_board")]
+    pub t: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub _id: DocumentId,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub _rev: String,
+    #[serde(rename = "bc")]
+    pub board_code: String,
+    #[serde(default)]
+    pub locked: bool,
+}
+
+/* a staff member who can log in to web. the password is kept as an argon2
+ * hash, and accounts with a TOTP secret need a code from it as well.
+ */
+#[derive(Serialize, Deserialize, CouchDocument, Clone)]
+pub struct StaffAccount
+{
+    #[serde(default = "_staff")]
+    pub t: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub _id: DocumentId,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub _rev: String,
+    pub username: String,
+    pub password: String,
+    pub role: Role,
+    #[serde(default)]
+    pub boards: Option<Vec<String>>, /* none for every board */
+    #[serde(default)]
+    pub totp_secret: Option<String>, /* base32 */
+    #[serde(default)]
+    pub totp_last_step: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterField
+{
+    Comment,
+    Name,
+    Email,
+    Subject,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction
+{
+    Reject,
+    Replace,
+    Flag,
+}
+
+/* a word filter, on one board or, without a board code, on all of them.
+ * the pattern is taken literally unless `regex` is set. rejected posts fail
+ * with the status for the field, replaced text becomes `replacement`, and
+ * flagged posts go through but are marked for review.
+ */
+#[derive(Serialize, Deserialize, CouchDocument, Clone)]
+pub struct Filter
+{
+    #[serde(default = "_filter")]
+    pub t: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub _id: DocumentId,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub _rev: String,
+    #[serde(rename = "bc")]
+    pub board_code: Option<String>,
+    pub field: FilterField,
+    pub pattern: String,
+    #[serde(default)]
+    pub regex: bool,
+    pub action: FilterAction,
+    #[serde(default)]
+    pub replacement: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -127,7 +345,7 @@ pub enum Message
         data: NewThreadMessage,
         request_id: Uuid,
         remote_ip: IpAddr,
-        role: Role,
+        grant: Grant,
         board_code: String,
     },
     NewComment
@@ -135,7 +353,7 @@ pub enum Message
         data: NewCommentMessage,
         request_id: Uuid,
         remote_ip: IpAddr,
-        role: Role,
+        grant: Grant,
         board_code: String,
     },
     PruneThreads
@@ -148,6 +366,71 @@ pub enum Message
         all_boards: bool,
         board_code: Option<String>,
     },
+    LockThread
+    {
+        thread_id: String,
+        board_code: String,
+        locked: bool,
+        grant: Grant,
+    },
+    LockBoard
+    {
+        board_code: String,
+        locked: bool,
+        grant: Grant,
+    },
+    SetPinned
+    {
+        thread_id: String,
+        board_code: String,
+        pinned: bool,
+        priority: i32,
+        grant: Grant,
+    },
+    DeletePost
+    {
+        post_id: String,
+        board_code: String,
+        mode: DeleteMode,
+        ban: Option<BanPoster>,
+        grant: Grant,
+    },
+    DeleteThread
+    {
+        thread_id: String,
+        board_code: String,
+        mode: DeleteMode,
+        ban: Option<BanPoster>,
+        grant: Grant,
+    },
+    UserDeletePost
+    {
+        post_id: String,
+        board_code: String,
+        password_hash: String, /* see prehash_secret */
+    },
+}
+
+/* hidden posts stay in the main db for the record but are shown nowhere;
+ * removed ones are gone, along with files no other post has.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeleteMode
+{
+    Hide,
+    Remove,
+}
+
+/* a ban on whoever made a post, issued along with deleting it. it is
+ * recorded as the work of the staff member the delete's grant belongs to.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BanPoster
+{
+    pub reason: String,
+    pub hours: i64, /* 0 for a permanent ban */
+    pub all_boards: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -155,6 +438,10 @@ pub struct NewThreadMessage
 {
     pub subject: String,
     pub body: PostBody,
+    #[serde(default)]
+    pub upload: Option<Upload>,
+    #[serde(default)]
+    pub delete_secret: Option<DeleteSecret>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -162,9 +449,25 @@ pub struct NewCommentMessage
 {
     pub parent_thread_id: String,
     pub body: PostBody,
+    #[serde(default)]
+    pub upload: Option<Upload>,
+    #[serde(default)]
+    pub delete_secret: Option<DeleteSecret>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/* what a poster can delete their post with, as the web server hands it on:
+ * a password they chose, or a random token kept in their session. both are
+ * pre-hashed with prehash_secret.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum DeleteSecret
+{
+    Password(String),
+    Token(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum Role
 {
     Admin,
@@ -173,7 +476,96 @@ pub enum Role
     User,
 }
 
-#[derive(Debug)]
+/* things only some staff may do */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Capability
+{
+    DeletePosts,
+    Ban,
+    Lock,
+    Pin,
+    MoveThreads,
+    EditFilters,
+    ManageBoards,
+}
+
+impl Role
+{
+    /* the permission matrix. admins can do anything, mods anything short of
+     * managing boards, and jannies only clean up posts.
+     */
+    pub fn can(&self, capability: Capability) -> bool
+    {
+        match self
+        {
+            Role::Admin => true,
+            Role::Mod => capability != Capability::ManageBoards,
+            Role::Janny => capability == Capability::DeletePosts,
+            Role::User => false,
+        }
+    }
+}
+
+/* a role held on every board, or only on the boards listed, along with
+ * the staff member it belongs to
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Grant
+{
+    pub role: Role,
+    #[serde(default)]
+    pub boards: Option<Vec<String>>,
+    #[serde(default)]
+    pub staff: Option<String>,
+}
+
+impl Grant
+{
+    pub fn global(role: Role) -> Self
+    {
+        Grant {
+            role,
+            boards: None,
+            staff: None,
+        }
+    }
+
+    /* what anonymous posters get */
+    pub fn user() -> Self
+    {
+        Grant::global(Role::User)
+    }
+
+    pub fn covers(&self, board_code: &str) -> bool
+    {
+        self.boards
+            .as_ref()
+            .is_none_or(|b| b.iter().any(|bc| bc == board_code))
+    }
+
+    pub fn allows(&self, capability: Capability, board_code: &str) -> bool
+    {
+        self.role.can(capability) && self.covers(board_code)
+    }
+
+    pub fn check(
+        &self,
+        capability: Capability,
+        board_code: &str,
+    ) -> Result<(), DispatchError>
+    {
+        if self.allows(capability, board_code)
+        {
+            Ok(())
+        }
+        else
+        {
+            Err(DispatchError::Forbidden(capability))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub enum PostStatus
 {
     BannedIp,
@@ -184,7 +576,10 @@ pub enum PostStatus
     BannedName,
     BannedEmail,
     ThreadArchived,
+    NoSuchThread,
     LargeThread,
+    ImageLimit,
+    LargeSubject,
     LargeName,
     LargeComment,
     LargeEmail,
@@ -201,6 +596,15 @@ pub enum DispatchError
     NewThreadFailed,
     NewThreadCreatedWithError,
     NewCommentFailed,
+    NewCommentCreatedWithError,
+    PruneFailed,
+    PublishFeedFailed,
+    LockFailed,
+    PinFailed,
+    DeleteFailed,
+    Forbidden(Capability),
+    RequestInProgress,
+    RequestClaimFailed,
 }
 
 #[derive(Clone)]
@@ -208,11 +612,32 @@ pub struct SpriteSettings
 {
     pub run_host: String,
     pub post_op_max_length: i64,
+    pub post_op_max_subject_length: i64,
     pub post_op_max_file_size: i64,
     pub post_comment_max_length: i64,
     pub post_comment_max_file_size: i64,
-    pub thread_max_comments: i64,
+    pub post_delete_window_secs: i64,
+    pub thread_max_comments: HashMap<String, i64>,
+    pub thread_bump_limit: HashMap<String, i64>,
+    pub thread_image_limit: HashMap<String, i64>,
     pub board_max_threads: i64,
+    pub board_threads_per_page: i64,
+    pub feed_output_dir: String,
+    pub feed_max_items: i64,
+    pub feed_base_url: String,
+    pub file_dir: String,
+    pub file_thumb_size: i64,
+    pub file_duplicate_window: i64,
+    pub file_types: HashMap<String, Vec<String>>,
+    pub file_max_dimension: i64,
+    pub file_max_decode_bytes: i64,
+    pub ban_cache_secs: i64,
+    pub cooldown_thread: HashMap<String, i64>,
+    pub cooldown_reply: HashMap<String, i64>,
+    pub cooldown_delete: HashMap<String, i64>,
+    pub rate_max_posts: i64,
+    pub rate_window_secs: i64,
+    pub filter_reload_secs: i64,
 }
 
 pub struct CouchSettings
@@ -224,16 +649,93 @@ pub struct CouchSettings
     pub db_listing: String,
 }
 
+#[derive(Clone)]
+pub struct WebSettings
+{
+    pub session_key: Option<String>,
+    pub session_secure: bool,
+    pub session_max_age_secs: i64,
+    pub trusted_proxies: Vec<IpAddr>,
+    pub login_max_attempts: i64,
+    pub login_window_secs: i64,
+}
+
 pub struct RedisSettings
 {
     pub connection_string: String,
+    pub stream_max_length: i64,
+    pub stream_group: String,
+    pub stream_claim_idle_ms: i64,
+}
+
+#[derive(Clone)]
+pub struct RetrySettings
+{
+    pub max_attempts: i64,
+    pub base_delay_ms: i64,
+    pub max_delay_ms: i64,
+}
+
+pub fn get_retry_settings(s: &Config) -> Result<RetrySettings, ConfigError>
+{
+    let ma = s.get_int("worker.retry.max-attempts")?;
+    let bdm = s.get_int("worker.retry.base-delay-ms")?;
+    let mdm = s.get_int("worker.retry.max-delay-ms")?;
+    Ok(RetrySettings {
+        max_attempts: ma,
+        base_delay_ms: bdm,
+        max_delay_ms: mdm,
+    })
+}
+
+impl RetrySettings
+{
+    /* delay before retrying after the given (1-based) failed attempt,
+     * doubling each time up to the configured maximum.
+     */
+    pub fn delay(&self, attempt: u32) -> Duration
+    {
+        let factor = 2_i64.saturating_pow(attempt.saturating_sub(1));
+        let ms = self
+            .base_delay_ms
+            .saturating_mul(factor)
+            .min(self.max_delay_ms);
+        Duration::from_millis(ms.max(0) as u64)
+    }
 }
 
 pub fn get_redis_settings(s: &Config) -> Result<RedisSettings, ConfigError>
 {
     let cs = s.get_string("redis.connection-string")?;
+    let sml = s.get_int("redis.stream.max-length")?;
+    let sg = s.get_string("redis.stream.group")?;
+    let scim = s.get_int("redis.stream.claim-idle-ms")?;
     Ok(RedisSettings {
         connection_string: cs,
+        stream_max_length: sml,
+        stream_group: sg,
+        stream_claim_idle_ms: scim,
+    })
+}
+
+pub fn get_web_settings(s: &Config) -> Result<WebSettings, ConfigError>
+{
+    // best kept out of settings.toml, which is checked in
+    let sk = std::env::var("SPRITEIB_SESSION_KEY")
+        .ok()
+        .or_else(|| s.get_string("web.session.key").ok());
+    let ss = s.get_bool("web.session.secure")?;
+    let smas = s.get_int("web.session.max-age-secs")?;
+    let tp = s.get::<Vec<IpAddr>>("web.trusted-proxies")?;
+    let lma = s.get_int("web.login.max-attempts")?;
+    let lws = s.get_int("web.login.window-secs")?;
+    Ok(WebSettings {
+        session_key: sk,
+        session_secure: ss,
+        session_max_age_secs: smas,
+        trusted_proxies: tp,
+        login_max_attempts: lma,
+        login_window_secs: lws,
     })
 }
 
@@ -258,137 +760,1282 @@ pub fn get_sprite_settings(s: &Config) -> Result<SpriteSettings, ConfigError>
 {
     let rh = s.get_string("spriteib.run.host")?;
     let poml = s.get_int("spriteib.post.op.max-length")?;
+    let pomsl = s.get_int("spriteib.post.op.max-subject-length")?;
     let pomfs = s.get_int("spriteib.post.op.max-file-size")?;
     let pcml = s.get_int("spriteib.post.comment.max-length")?;
     let pcmfs = s.get_int("spriteib.post.comment.max-file-size")?;
-    let tmc = s.get_int("spriteib.thread.max-comments")?;
+    let pdws = s.get_int("spriteib.post.delete-window-secs")?;
+    /* a bare number, as thread.max-comments used to be, is the default */
+    let tmc = match s.get_int("spriteib.thread.max-comments")
+    {
+        Ok(n) => HashMap::from([("default".to_string(), n)]),
+        Err(_) => s.get("spriteib.thread.max-comments")?,
+    };
+    let tbl = s.get::<HashMap<String, i64>>("spriteib.thread.bump-limit")?;
+    let til = s.get::<HashMap<String, i64>>("spriteib.thread.image-limit")?;
     let bmt = s.get_int("spriteib.board.max-threads")?;
+    let btpp = s.get_int("spriteib.board.threads-per-page")?;
+    let fod = s.get_string("spriteib.feed.output-dir")?;
+    let fmi = s.get_int("spriteib.feed.max-items")?;
+    let fbu = s.get_string("spriteib.feed.base-url")?;
+    let fd = s.get_string("spriteib.file.dir")?;
+    let fts = s.get_int("spriteib.file.thumb-size")?;
+    let fdw = s.get_int("spriteib.file.duplicate-window")?;
+    let ft = s.get::<HashMap<String, Vec<String>>>("spriteib.file.types")?;
+    let fmd = s.get_int("spriteib.file.max-dimension")?;
+    let fmdb = s.get_int("spriteib.file.max-decode-bytes")?;
+    let bcs = s.get_int("spriteib.ban.cache-secs")?;
+    let ct = s.get::<HashMap<String, i64>>("spriteib.cooldown.thread")?;
+    let cr = s.get::<HashMap<String, i64>>("spriteib.cooldown.reply")?;
+    let cd = s.get::<HashMap<String, i64>>("spriteib.cooldown.delete")?;
+    let rmp = s.get_int("spriteib.rate.max-posts")?;
+    let rws = s.get_int("spriteib.rate.window-secs")?;
+    let frs = s.get_int("spriteib.filter.reload-secs")?;
 
     Ok(SpriteSettings {
         run_host: rh,
         post_op_max_length: poml,
+        post_op_max_subject_length: pomsl,
         post_op_max_file_size: pomfs,
         post_comment_max_length: pcml,
         post_comment_max_file_size: pcmfs,
+        post_delete_window_secs: pdws,
         thread_max_comments: tmc,
+        thread_bump_limit: tbl,
+        thread_image_limit: til,
         board_max_threads: bmt,
+        board_threads_per_page: btpp,
+        feed_output_dir: fod,
+        feed_max_items: fmi,
+        feed_base_url: fbu,
+        file_dir: fd,
+        file_thumb_size: fts,
+        file_duplicate_window: fdw,
+        file_types: ft,
+        file_max_dimension: fmd,
+        file_max_decode_bytes: fmdb,
+        ban_cache_secs: bcs,
+        cooldown_thread: ct,
+        cooldown_reply: cr,
+        cooldown_delete: cd,
+        rate_max_posts: rmp,
+        rate_window_secs: rws,
+        filter_reload_secs: frs,
     })
 }
 
-impl fmt::Display for PostStatus
+/* per-board settings are tables keyed on board code, with boards that
+ * aren't listed falling back to "default".
+ */
+fn for_board<'a, T>(
+    table: &'a HashMap<String, T>,
+    board_code: &str,
+) -> Option<&'a T>
 {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
-    {
-        write!(f, "{:?}", self)
-    }
+    table.get(board_code).or_else(|| table.get("default"))
 }
 
-impl fmt::Display for DispatchError
+impl SpriteSettings
 {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    /* MIME types that may be posted on a board */
+    pub fn file_types(&self, board_code: &str) -> &[String]
     {
-        write!(f, "{:?}", self)
+        for_board(&self.file_types, board_code)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
     }
-}
 
-#[derive(Clone)]
-pub struct RedisBus
-{
-    pub uri: String,
-    pub connection: Option<MultiplexedConnection>,
-}
+    /* seconds an address has to wait between threads on a board */
+    pub fn thread_cooldown(&self, board_code: &str) -> i64
+    {
+        for_board(&self.cooldown_thread, board_code)
+            .copied()
+            .unwrap_or(0)
+    }
 
-#[derive(Debug)]
-pub enum BusError
-{
-    RedisError(RedisError),
-    MissingConnection,
-}
+    /* seconds an address has to wait between replies on a board */
+    pub fn reply_cooldown(&self, board_code: &str) -> i64
+    {
+        for_board(&self.cooldown_reply, board_code)
+            .copied()
+            .unwrap_or(0)
+    }
 
-impl RedisBus
-{
-    pub async fn connect(&mut self) -> Result<(), RedisError>
+    /* seconds an address has to wait between deleting its own posts on a
+     * board
+     */
+    pub fn delete_cooldown(&self, board_code: &str) -> i64
     {
-        let client = redis::Client::open(self.uri.clone()).expect("db wrong");
-        let connection = client.get_multiplexed_async_connection().await?;
-        self.connection = Some(connection);
-        Ok(())
+        for_board(&self.cooldown_delete, board_code)
+            .copied()
+            .unwrap_or(0)
     }
 
-    pub async fn pubsub(&mut self) -> Result<PubSub, RedisError>
+    /* replies a thread on a board takes before it stops accepting them */
+    pub fn max_comments(&self, board_code: &str) -> i64
     {
-        // since pubsub performs a multicast for all nodes in a cluster,
-        // listening to a single server in the cluster is sufficient for cluster setups
-        let client = Client::open(self.uri.clone())?;
-        client.get_async_pubsub().await
+        for_board(&self.thread_max_comments, board_code)
+            .copied()
+            .unwrap_or(i64::MAX)
     }
 
-    pub async fn publish(
-        &mut self,
-        channel: &str,
-        message: &str,
-    ) -> Result<(), BusError>
+    /* replies after which a thread on a board is no longer bumped */
+    pub fn bump_limit(&self, board_code: &str) -> i64
     {
-        let ps = &mut self.connection;
-        match ps
-        {
-            Some(conn) => match conn
-                .publish::<&str, String, String>(channel, message.to_string())
-                .await
-            {
-                Ok(_) => Ok(()),
-                Err(e) => Err(BusError::RedisError(e)),
-            },
-            None => Err(BusError::MissingConnection),
-        }
+        for_board(&self.thread_bump_limit, board_code)
+            .copied()
+            .unwrap_or(i64::MAX)
     }
 
-    pub async fn set_key(
-        &mut self,
-        key: &str,
-        value: impl ToRedisArgs,
-        expiry: i32,
-    ) -> Result<(), BusError>
+    /* replies with files a thread on a board takes */
+    pub fn image_limit(&self, board_code: &str) -> i64
     {
-        let ps = &mut self.connection;
-        match ps
-        {
-            Some(conn) =>
-            {
-                let mut base_cmd = redis::cmd("SET");
-                let mut cmd = base_cmd.arg(key).arg(value);
-                if expiry > 0
-                {
-                    cmd = cmd.arg("EX").arg(expiry);
-                }
-                match conn.send_packed_command(cmd).await
-                {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err(BusError::RedisError(e)),
-                }
-            }
-            None => Err(BusError::MissingConnection),
-        }
+        for_board(&self.thread_image_limit, board_code)
+            .copied()
+            .unwrap_or(i64::MAX)
     }
+}
 
-    pub async fn set_status(
-        &mut self,
-        request_id: String,
-        message: String,
-        duration: i32,
-    ) -> Result<(), BusError>
+/* an address and prefix length, from either a bare address or one in CIDR
+ * notation. a range of v4 addresses mapped into v6 comes back as plain v4.
+ */
+pub fn parse_range(range: &str) -> Option<(IpAddr, u32)>
+{
+    let (addr, prefix) = match range.split_once('/')
     {
-        self.set_key(&request_id, message, duration).await
+        Some((a, p)) => (a.parse::<IpAddr>().ok()?, Some(p.parse().ok()?)),
+        None => (range.parse::<IpAddr>().ok()?, None),
+    };
+
+    let bits = if addr.is_ipv4() { 32 } else { 128 };
+    let (addr, prefix) = match prefix
+    {
+        Some(p) if p > bits => return None,
+        Some(p) => (addr, p),
+        None => (addr, bits),
+    };
+
+    match addr
+    {
+        IpAddr::V6(a) if prefix >= 96 => match a.to_ipv4_mapped()
+        {
+            Some(v4) => Some((IpAddr::V4(v4), prefix - 96)),
+            None => Some((addr, prefix)),
+        },
+        _ => Some((addr, prefix)),
     }
 }
 
-async fn seed_data(db: Database)
+impl Ban
 {
-    for n in 1..120
+    pub fn covers(&self, ip: &IpAddr) -> bool
     {
-        let p = Thread {
-            t: _thread(),
-            _id: "".to_string(),
+        let (addr, prefix) = match parse_range(&self.range)
+        {
+            Some(r) => r,
+            None => return false,
+        };
+
+        // v4 addresses mapped into v6 are compared as plain v4
+        let (addr, ip, bits) = match (addr, ip.to_canonical())
+        {
+            (IpAddr::V4(a), IpAddr::V4(b)) =>
+            {
+                (u32::from(a) as u128, u32::from(b) as u128, 32)
+            }
+            (IpAddr::V6(a), IpAddr::V6(b)) =>
+            {
+                (u128::from(a), u128::from(b), 128)
+            }
+            _ => return false,
+        };
+
+        let shift = bits - prefix;
+        shift == bits || addr >> shift == ip >> shift
+    }
+
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool
+    {
+        self.expires.is_none_or(|e| e > now)
+    }
+}
+
+/* a deletion password or token as it is sent to the workers, so that
+ * what the poster typed never goes on the streams or into dead letters
+ */
+pub fn prehash_secret(secret: &str) -> String
+{
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+// marks the hashes of deletion tokens, as against argon2 ones
+const TOKEN_HASH_PREFIX: &str = "sha256:";
+
+impl DeleteSecret
+{
+    pub fn hash(&self) -> &str
+    {
+        match self
+        {
+            DeleteSecret::Password(h) | DeleteSecret::Token(h) => h,
+        }
+    }
+
+    /* what is kept with the post. passwords can be weak, so they get
+     * argon2; tokens are random, and hashing them once more will do.
+     */
+    pub fn stored_hash(&self) -> Result<String, password_hash::Error>
+    {
+        match self
+        {
+            DeleteSecret::Password(h) => hash_secret(h),
+            DeleteSecret::Token(h) =>
+            {
+                Ok(format!("{}{}", TOKEN_HASH_PREFIX, prehash_secret(h)))
+            }
+        }
+    }
+}
+
+/* whether a pre-hashed password or token matches what a post was stored
+ * with. only passwords are slow to check.
+ */
+pub fn verify_delete_hash(stored: &str, hash: &str) -> bool
+{
+    match stored.strip_prefix(TOKEN_HASH_PREFIX)
+    {
+        Some(h) => h == prehash_secret(hash),
+        None => verify_secret(stored, hash),
+    }
+}
+
+/* argon2 hashes of staff and deletion passwords. both are slow on
+ * purpose; keep them off the async runtime.
+ */
+pub fn hash_secret(secret: &str) -> Result<String, password_hash::Error>
+{
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(secret.as_bytes(), &salt)?
+        .to_string())
+}
+
+pub fn verify_secret(hash: &str, secret: &str) -> bool
+{
+    match PasswordHash::new(hash)
+    {
+        Ok(hash) => Argon2::default()
+            .verify_password(secret.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+impl StaffAccount
+{
+    pub fn grant(&self) -> Grant
+    {
+        Grant {
+            role: self.role,
+            boards: self.boards.clone(),
+            staff: Some(self.username.clone()),
+        }
+    }
+
+    pub fn set_password(
+        &mut self,
+        password: &str,
+    ) -> Result<(), password_hash::Error>
+    {
+        self.password = hash_secret(password)?;
+        Ok(())
+    }
+
+    pub fn verify_password(&self, password: &str) -> bool
+    {
+        verify_secret(&self.password, password)
+    }
+
+    /* a new secret for the account, returned as an otpauth:// URL for
+     * authenticator apps
+     */
+    pub fn enable_totp(&mut self) -> String
+    {
+        let mut secret = [0u8; 20];
+        OsRng.fill_bytes(&mut secret);
+        let encoded = Secret::Raw(secret.to_vec()).to_encoded().to_string();
+        let url = format!(
+            "otpauth://totp/spriteib:{}?secret={}&issuer=spriteib",
+            self.username, encoded
+        );
+        self.totp_secret = Some(encoded);
+        self.totp_last_step = None;
+        url
+    }
+
+    /* accounts without a secret pass without a code. a code is good for
+     * the time step before and after its own, but only once; the step it
+     * was accepted for is kept, and the account has to be saved after.
+     */
+    pub fn verify_totp(&mut self, code: &str) -> bool
+    {
+        let secret = match &self.totp_secret
+        {
+            Some(s) => s,
+            None => return true,
+        };
+
+        let totp = Secret::Encoded(secret.clone())
+            .to_bytes()
+            .ok()
+            .and_then(|b| TOTP::new(Algorithm::SHA1, 6, 0, 30, b).ok());
+        let totp = match totp
+        {
+            Some(totp) => totp,
+            None => return false,
+        };
+
+        let now = Utc::now().timestamp().max(0) as u64;
+        let current = now / totp.step;
+        let first = match self.totp_last_step
+        {
+            Some(last) => (last + 1).max(current.saturating_sub(1)),
+            None => current.saturating_sub(1),
+        };
+
+        for step in first..=current + 1
+        {
+            if totp.check(code.trim(), step * totp.step)
+            {
+                self.totp_last_step = Some(step);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl FilterField
+{
+    /* what a post that trips a rejecting filter on this field fails with */
+    pub fn rejection(&self) -> PostStatus
+    {
+        match self
+        {
+            FilterField::Comment | FilterField::Subject =>
+            {
+                PostStatus::BannedWord
+            }
+            FilterField::Name => PostStatus::BannedName,
+            FilterField::Email => PostStatus::BannedEmail,
+        }
+    }
+}
+
+impl fmt::Display for PostStatus
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl DispatchError
+{
+    /* failures to reach CouchDB or Redis, which are worth retrying. post
+     * requests pick up where they left off, so this includes those that
+     * failed after the post was written.
+     */
+    pub fn is_transient(&self) -> bool
+    {
+        match self
+        {
+            DispatchError::NewThreadFailed
+            | DispatchError::NewThreadCreatedWithError
+            | DispatchError::NewCommentFailed
+            | DispatchError::NewCommentCreatedWithError
+            | DispatchError::PruneFailed
+            | DispatchError::PublishFeedFailed
+            | DispatchError::LockFailed
+            | DispatchError::PinFailed
+            | DispatchError::DeleteFailed
+            | DispatchError::RequestClaimFailed => true,
+            // waited out by the worker rather than counted as a failure
+            DispatchError::RequestInProgress => false,
+            DispatchError::Forbidden(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for DispatchError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Clone)]
+pub struct RedisBus
+{
+    pub uri: String,
+    pub connection: Option<MultiplexedConnection>,
+    pub stream_max_length: usize,
+}
+
+#[derive(Debug)]
+pub enum BusError
+{
+    RedisError(RedisError),
+    MissingConnection,
+}
+
+/* a message read from one of the channel streams. it stays pending for its
+ * consumer group until acknowledged with its id.
+ */
+#[derive(Debug)]
+pub struct BusMessage
+{
+    pub channel: String,
+    pub id: String,
+    pub payload: String,
+}
+
+/* a message that could not be handled, kept with what went wrong so that
+ * it can be inspected and put back on its channel.
+ */
+#[derive(Debug)]
+pub struct DeadLetter
+{
+    pub channel: String,
+    pub payload: String,
+    pub error: String,
+    pub attempts: u32,
+    pub time: DateTime<Utc>,
+}
+
+const DEAD_LETTER_STREAM: &str = "stream:dead";
+
+fn dead_letter(sid: &StreamId) -> DeadLetter
+{
+    DeadLetter {
+        channel: sid.get("channel").unwrap_or_default(),
+        payload: sid.get("payload").unwrap_or_default(),
+        error: sid.get("error").unwrap_or_default(),
+        attempts: sid.get("attempts").unwrap_or_default(),
+        time: sid
+            .get::<String>("time")
+            .and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+            .map(|t| t.with_timezone(&Utc))
+            .unwrap_or_default(),
+    }
+}
+
+pub fn stream_key(channel: &str) -> String
+{
+    format!("stream:{}", channel)
+}
+
+fn bus_message(key: &str, sid: StreamId) -> BusMessage
+{
+    BusMessage {
+        channel: key.strip_prefix("stream:").unwrap_or(key).to_string(),
+        payload: sid.get::<String>("payload").unwrap_or_default(),
+        id: sid.id,
+    }
+}
+
+impl RedisBus
+{
+    pub async fn connect(&mut self) -> Result<(), RedisError>
+    {
+        let client = redis::Client::open(self.uri.clone()).expect("db wrong");
+        let connection = client.get_multiplexed_async_connection().await?;
+        self.connection = Some(connection);
+        Ok(())
+    }
+
+    /* messages are appended to a stream per channel rather than sent with
+     * PUBLISH, so that they wait for a worker instead of being dropped when
+     * none is listening.
+     */
+    pub async fn publish(
+        &mut self,
+        channel: &str,
+        message: &str,
+    ) -> Result<(), BusError>
+    {
+        let maxlen = StreamMaxlen::Approx(self.stream_max_length);
+        let ps = &mut self.connection;
+        match ps
+        {
+            Some(conn) => match conn
+                .xadd_maxlen::<String, &str, &str, &str, String>(
+                    stream_key(channel),
+                    maxlen,
+                    "*",
+                    &[("payload", message)],
+                )
+                .await
+            {
+                Ok(_) => Ok(()),
+                Err(e) => Err(BusError::RedisError(e)),
+            },
+            None => Err(BusError::MissingConnection),
+        }
+    }
+
+    /* create the consumer group for a channel, along with its stream. a new
+     * group starts from the beginning of the stream so nothing published
+     * before the first worker started is lost.
+     */
+    pub async fn create_group(
+        &mut self,
+        channel: &str,
+        group: &str,
+    ) -> Result<(), BusError>
+    {
+        let ps = &mut self.connection;
+        match ps
+        {
+            Some(conn) => match conn
+                .xgroup_create_mkstream::<String, &str, &str, ()>(
+                    stream_key(channel),
+                    group,
+                    "0",
+                )
+                .await
+            {
+                Ok(_) => Ok(()),
+                Err(e) if e.code() == Some("BUSYGROUP") => Ok(()),
+                Err(e) => Err(BusError::RedisError(e)),
+            },
+            None => Err(BusError::MissingConnection),
+        }
+    }
+
+    /* read new messages for this consumer, waiting up to `block_ms` for
+     * one to arrive. this blocks the connection it runs on, so it should
+     * have a connection of its own.
+     */
+    pub async fn read_group(
+        &mut self,
+        channels: &[&str],
+        group: &str,
+        consumer: &str,
+        count: usize,
+        block_ms: usize,
+    ) -> Result<Vec<BusMessage>, BusError>
+    {
+        let keys = channels
+            .iter()
+            .map(|c| stream_key(c))
+            .collect::<Vec<String>>();
+        let ids = vec![">"; keys.len()];
+        let opts = StreamReadOptions::default()
+            .group(group, consumer)
+            .count(count)
+            .block(block_ms);
+
+        let ps = &mut self.connection;
+        match ps
+        {
+            Some(conn) => match conn
+                .xread_options::<String, &str, Option<StreamReadReply>>(
+                    &keys, &ids, &opts,
+                )
+                .await
+            {
+                Ok(reply) => Ok(reply
+                    .map(|r| r.keys)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .flat_map(|k| {
+                        let key = k.key;
+                        k.ids
+                            .into_iter()
+                            .map(move |sid| bus_message(&key, sid))
+                    })
+                    .collect()),
+                Err(e) => Err(BusError::RedisError(e)),
+            },
+            None => Err(BusError::MissingConnection),
+        }
+    }
+
+    pub async fn ack(
+        &mut self,
+        channel: &str,
+        group: &str,
+        id: &str,
+    ) -> Result<(), BusError>
+    {
+        let ps = &mut self.connection;
+        match ps
+        {
+            Some(conn) => match conn
+                .xack::<String, &str, &str, i64>(
+                    stream_key(channel),
+                    group,
+                    &[id],
+                )
+                .await
+            {
+                Ok(_) => Ok(()),
+                Err(e) => Err(BusError::RedisError(e)),
+            },
+            None => Err(BusError::MissingConnection),
+        }
+    }
+
+    /* take over at most `count` messages that some consumer of the group
+     * read but has not acknowledged within `min_idle_ms`, e.g. because its
+     * worker crashed.
+     */
+    pub async fn autoclaim(
+        &mut self,
+        channel: &str,
+        group: &str,
+        consumer: &str,
+        min_idle_ms: usize,
+        count: usize,
+    ) -> Result<Vec<BusMessage>, BusError>
+    {
+        let key = stream_key(channel);
+        let mut start = "0-0".to_string();
+        let mut claimed = Vec::<BusMessage>::new();
+
+        let ps = &mut self.connection;
+        let conn = match ps
+        {
+            Some(conn) => conn,
+            None => return Err(BusError::MissingConnection),
+        };
+
+        loop
+        {
+            let reply: StreamAutoClaimReply = conn
+                .xautoclaim_options(
+                    &key,
+                    group,
+                    consumer,
+                    min_idle_ms,
+                    &start,
+                    StreamAutoClaimOptions::default()
+                        .count(count - claimed.len()),
+                )
+                .await
+                .map_err(BusError::RedisError)?;
+
+            claimed.extend(
+                reply.claimed.into_iter().map(|sid| bus_message(&key, sid)),
+            );
+
+            /* a cursor of 0-0 means the whole pending list has been scanned */
+            if reply.next_stream_id == "0-0" || claimed.len() >= count
+            {
+                return Ok(claimed);
+            }
+            start = reply.next_stream_id;
+        }
+    }
+
+    /* reset the idle time of a message the consumer is still working on,
+     * so that it isn't claimed away from under it.
+     */
+    pub async fn keep_claim(
+        &mut self,
+        channel: &str,
+        group: &str,
+        consumer: &str,
+        id: &str,
+    ) -> Result<(), BusError>
+    {
+        let ps = &mut self.connection;
+        match ps
+        {
+            Some(conn) => conn
+                .xclaim_options::<String, &str, &str, i64, &str, redis::Value>(
+                    stream_key(channel),
+                    group,
+                    consumer,
+                    0,
+                    &[id],
+                    StreamClaimOptions::default().with_justid(),
+                )
+                .await
+                .map(|_| ())
+                .map_err(BusError::RedisError),
+            None => Err(BusError::MissingConnection),
+        }
+    }
+
+    pub async fn set_key(
+        &mut self,
+        key: &str,
+        value: impl ToRedisArgs,
+        expiry: i32,
+    ) -> Result<(), BusError>
+    {
+        let ps = &mut self.connection;
+        match ps
+        {
+            Some(conn) =>
+            {
+                let mut base_cmd = redis::cmd("SET");
+                let mut cmd = base_cmd.arg(key).arg(value);
+                if expiry > 0
+                {
+                    cmd = cmd.arg("EX").arg(expiry);
+                }
+                match conn.send_packed_command(cmd).await
+                {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(BusError::RedisError(e)),
+                }
+            }
+            None => Err(BusError::MissingConnection),
+        }
+    }
+
+    pub async fn get_key(
+        &mut self,
+        key: &str,
+    ) -> Result<Option<String>, BusError>
+    {
+        let ps = &mut self.connection;
+        match ps
+        {
+            Some(conn) => match conn.get::<&str, Option<String>>(key).await
+            {
+                Ok(v) => Ok(v),
+                Err(e) => Err(BusError::RedisError(e)),
+            },
+            None => Err(BusError::MissingConnection),
+        }
+    }
+
+    pub async fn get_status(
+        &mut self,
+        request_id: &str,
+    ) -> Result<Option<String>, BusError>
+    {
+        self.get_key(request_id).await
+    }
+
+    /* take a request for processing unless someone else holds it. the claim
+     * lapses after `expiry` seconds, in case its holder dies.
+     */
+    pub async fn claim_request(
+        &mut self,
+        request_id: &str,
+        token: &str,
+        expiry: i32,
+    ) -> Result<bool, BusError>
+    {
+        let ps = &mut self.connection;
+        match ps
+        {
+            Some(conn) =>
+            {
+                let mut cmd = redis::cmd("SET");
+                cmd.arg(claim_key(request_id))
+                    .arg(token)
+                    .arg("NX")
+                    .arg("EX")
+                    .arg(expiry);
+                match cmd.query_async::<Option<String>>(conn).await
+                {
+                    Ok(v) => Ok(v.is_some()),
+                    Err(e) => Err(BusError::RedisError(e)),
+                }
+            }
+            None => Err(BusError::MissingConnection),
+        }
+    }
+
+    /* give up a claim, but only if it is still ours */
+    pub async fn release_request(
+        &mut self,
+        request_id: &str,
+        token: &str,
+    ) -> Result<(), BusError>
+    {
+        let script = Script::new(
+            "if redis.call('GET', KEYS[1]) == ARGV[1] then
+                return redis.call('DEL', KEYS[1])
+            end
+            return 0",
+        );
+
+        let ps = &mut self.connection;
+        match ps
+        {
+            Some(conn) => script
+                .key(claim_key(request_id))
+                .arg(token)
+                .invoke_async::<i64>(conn)
+                .await
+                .map(|_| ())
+                .map_err(BusError::RedisError),
+            None => Err(BusError::MissingConnection),
+        }
+    }
+
+    pub async fn set_status(
+        &mut self,
+        request_id: String,
+        message: String,
+        duration: i32,
+    ) -> Result<(), BusError>
+    {
+        self.set_key(&request_id, message, duration).await
+    }
+
+    pub async fn dead_letter(
+        &mut self,
+        letter: &DeadLetter,
+    ) -> Result<(), BusError>
+    {
+        let maxlen = StreamMaxlen::Approx(self.stream_max_length);
+        let fields = [
+            ("channel", letter.channel.clone()),
+            ("payload", letter.payload.clone()),
+            ("error", letter.error.clone()),
+            ("attempts", letter.attempts.to_string()),
+            ("time", letter.time.to_rfc3339()),
+        ];
+
+        let ps = &mut self.connection;
+        match ps
+        {
+            Some(conn) => match conn
+                .xadd_maxlen::<&str, &str, &str, String, String>(
+                    DEAD_LETTER_STREAM,
+                    maxlen,
+                    "*",
+                    &fields,
+                )
+                .await
+            {
+                Ok(_) => Ok(()),
+                Err(e) => Err(BusError::RedisError(e)),
+            },
+            None => Err(BusError::MissingConnection),
+        }
+    }
+
+    /* the oldest `count` dead letters, with their ids */
+    pub async fn dead_letters(
+        &mut self,
+        count: usize,
+    ) -> Result<Vec<(String, DeadLetter)>, BusError>
+    {
+        let ps = &mut self.connection;
+        match ps
+        {
+            Some(conn) => match conn
+                .xrange_count::<&str, &str, &str, usize, StreamRangeReply>(
+                    DEAD_LETTER_STREAM,
+                    "-",
+                    "+",
+                    count,
+                )
+                .await
+            {
+                Ok(reply) => Ok(reply
+                    .ids
+                    .iter()
+                    .map(|sid| (sid.id.clone(), dead_letter(sid)))
+                    .collect()),
+                Err(e) => Err(BusError::RedisError(e)),
+            },
+            None => Err(BusError::MissingConnection),
+        }
+    }
+
+    /* publish a dead letter back onto its channel and drop it from the
+     * dead letter stream. returns false if there is no such letter.
+     */
+    pub async fn requeue_dead_letter(
+        &mut self,
+        id: &str,
+    ) -> Result<bool, BusError>
+    {
+        let letter = match &mut self.connection
+        {
+            Some(conn) => match conn
+                .xrange::<&str, &str, &str, StreamRangeReply>(
+                    DEAD_LETTER_STREAM,
+                    id,
+                    id,
+                )
+                .await
+            {
+                Ok(reply) => reply.ids.first().map(dead_letter),
+                Err(e) => return Err(BusError::RedisError(e)),
+            },
+            None => return Err(BusError::MissingConnection),
+        };
+
+        let letter = match letter
+        {
+            Some(l) => l,
+            None => return Ok(false),
+        };
+
+        self.publish(&letter.channel, &letter.payload).await?;
+
+        match &mut self.connection
+        {
+            Some(conn) => match conn
+                .xdel::<&str, &str, i64>(DEAD_LETTER_STREAM, &[id])
+                .await
+            {
+                Ok(_) => Ok(true),
+                Err(e) => Err(BusError::RedisError(e)),
+            },
+            None => Err(BusError::MissingConnection),
+        }
+    }
+
+    /* count a post against its address' cooldown on the board and against
+     * its cap across all boards, both at once. returns how many
+     * milliseconds are left to wait if either says no, or 0 if the post may
+     * go ahead. a request that already went through once goes through
+     * again without being counted twice.
+     */
+    pub async fn take_post_slot(
+        &mut self,
+        cooldown_key: &str,
+        ip: &IpAddr,
+        request_id: &str,
+        cooldown_secs: i64,
+        max_posts: i64,
+        window_secs: i64,
+    ) -> Result<i64, BusError>
+    {
+        let script = Script::new(
+            "if redis.call('EXISTS', KEYS[3]) == 1 then
+                return 0
+            end
+            local wait = redis.call('PTTL', KEYS[1])
+            if wait > 0 then
+                return wait
+            end
+            local max = tonumber(ARGV[2])
+            if max > 0 then
+                local count = tonumber(redis.call('GET', KEYS[2]) or '0')
+                if count >= max then
+                    return math.max(redis.call('PTTL', KEYS[2]), 1)
+                end
+            end
+            local cooldown = tonumber(ARGV[1])
+            if cooldown > 0 then
+                redis.call('SET', KEYS[1], 1, 'EX', cooldown)
+            end
+            if redis.call('INCR', KEYS[2]) == 1 then
+                redis.call('EXPIRE', KEYS[2], ARGV[3])
+            end
+            redis.call('SET', KEYS[3], 1, 'EX', ARGV[4])
+            return 0",
+        );
+
+        // long enough to outlast any retries of the request
+        let pass_ttl = 86400;
+
+        let ps = &mut self.connection;
+        match ps
+        {
+            Some(conn) => script
+                .key(cooldown_key)
+                .key(rate_key(ip))
+                .key(rate_pass_key(request_id))
+                .arg(cooldown_secs)
+                .arg(max_posts)
+                .arg(window_secs.max(1))
+                .arg(pass_ttl)
+                .invoke_async(conn)
+                .await
+                .map_err(BusError::RedisError),
+            None => Err(BusError::MissingConnection),
+        }
+    }
+
+    /* count an attempt at something against `max` per window. returns how
+     * many milliseconds are left of the window once it has had more than
+     * that, or 0 if the attempt may go ahead.
+     */
+    pub async fn take_attempt(
+        &mut self,
+        key: &str,
+        max: i64,
+        window_secs: i64,
+    ) -> Result<i64, BusError>
+    {
+        let script = Script::new(
+            "local count = redis.call('INCR', KEYS[1])
+            if count == 1 then
+                redis.call('EXPIRE', KEYS[1], ARGV[2])
+            end
+            if count > tonumber(ARGV[1]) then
+                return math.max(redis.call('PTTL', KEYS[1]), 1)
+            end
+            return 0",
+        );
+
+        let ps = &mut self.connection;
+        match ps
+        {
+            Some(conn) => script
+                .key(key)
+                .arg(max)
+                .arg(window_secs.max(1))
+                .invoke_async(conn)
+                .await
+                .map_err(BusError::RedisError),
+            None => Err(BusError::MissingConnection),
+        }
+    }
+
+    /* delete one key, if it is there */
+    pub async fn delete_key(&mut self, key: &str) -> Result<(), BusError>
+    {
+        let ps = &mut self.connection;
+        match ps
+        {
+            Some(conn) => conn
+                .del::<&str, i64>(key)
+                .await
+                .map(|_| ())
+                .map_err(BusError::RedisError),
+            None => Err(BusError::MissingConnection),
+        }
+    }
+
+    /* delete every key matching a glob pattern. this SCANs the whole
+     * keyspace, so it is best kept to rare calls.
+     */
+    pub async fn delete_keys(&mut self, pattern: &str)
+        -> Result<(), BusError>
+    {
+        let ps = &mut self.connection;
+        match ps
+        {
+            Some(conn) =>
+            {
+                let mut keys = Vec::new();
+                let mut iter = conn
+                    .scan_match::<&str, String>(pattern)
+                    .await
+                    .map_err(BusError::RedisError)?;
+                while let Some(key) = iter.next_item().await
+                {
+                    keys.push(key);
+                }
+                drop(iter);
+
+                if keys.is_empty()
+                {
+                    return Ok(());
+                }
+                conn.del::<Vec<String>, i64>(keys)
+                    .await
+                    .map(|_| ())
+                    .map_err(BusError::RedisError)
+            }
+            None => Err(BusError::MissingConnection),
+        }
+    }
+
+    /* threads and comments on a board share one number space, handed out
+     * by INCR so that no two workers can ever be given the same number.
+     */
+    pub async fn next_post_num(
+        &mut self,
+        board_code: &str,
+    ) -> Result<i32, BusError>
+    {
+        let ps = &mut self.connection;
+        match ps
+        {
+            Some(conn) => match conn
+                .incr::<String, i64, i64>(post_num_key(board_code), 1)
+                .await
+            {
+                Ok(n) => Ok(n as i32),
+                Err(e) => Err(BusError::RedisError(e)),
+            },
+            None => Err(BusError::MissingConnection),
+        }
+    }
+
+    /* raise a board's post number counter to at least `floor`, e.g. the
+     * highest number already stored in CouchDB. the counter is never
+     * lowered, so reseeding while other workers are allocating is safe.
+     */
+    pub async fn reseed_post_num(
+        &mut self,
+        board_code: &str,
+        floor: i64,
+    ) -> Result<i64, BusError>
+    {
+        let script = Script::new(
+            "local current = tonumber(redis.call('GET', KEYS[1]) or '0')
+            local floor = tonumber(ARGV[1])
+            if current < floor then
+                redis.call('SET', KEYS[1], floor)
+                return floor
+            end
+            return current",
+        );
+
+        let ps = &mut self.connection;
+        match ps
+        {
+            Some(conn) => script
+                .key(post_num_key(board_code))
+                .arg(floor)
+                .invoke_async(conn)
+                .await
+                .map_err(BusError::RedisError),
+            None => Err(BusError::MissingConnection),
+        }
+    }
+}
+
+pub fn claim_key(request_id: &str) -> String
+{
+    format!("claim:{}", request_id)
+}
+
+pub fn staging_path(file_dir: &str, request_id: &Uuid) -> PathBuf
+{
+    Path::new(file_dir)
+        .join("staging")
+        .join(request_id.simple().to_string())
+}
+
+pub fn board_doc_id(board_code: &str) -> String
+{
+    format!("board-{}", board_code)
+}
+
+pub fn staff_doc_id(username: &str) -> String
+{
+    format!("staff-{}", username)
+}
+
+pub fn ban_key(board_code: &str, ip: &IpAddr) -> String
+{
+    format!("ban:{}:{}", board_code, ip.to_canonical())
+}
+
+/* the ban, if any, that keeps `ip` from posting on the board. the answer is
+ * cached in Redis for `cache_secs`, so a ban can take that long to come
+ * into force or to lift. the cache is only a shortcut; when Redis can't be
+ * reached the bans are read from CouchDB.
+ */
+pub async fn find_ban(
+    db: &Database,
+    bus: &mut RedisBus,
+    board_code: &str,
+    ip: &IpAddr,
+    cache_secs: i64,
+) -> Result<Option<Ban>, CouchError>
+{
+    let key = ban_key(board_code, ip);
+    let now = Utc::now();
+
+    match bus.get_key(&key).await
+    {
+        Ok(Some(cached)) =>
+        {
+            if let Ok(ban) = serde_json::from_str::<Option<Ban>>(&cached)
+            {
+                return Ok(ban.filter(|b| b.is_active(now)));
+            }
+        }
+        Ok(None) => (),
+        Err(e) => warn!("error reading cached ban {}: {:?}", key, e),
+    }
+
+    // global bans sit under "*"
+    let qp = QueryParams::default()
+        .keys(vec!["*".to_string(), board_code.to_string()])
+        .include_docs(true);
+
+    let result: ViewCollection<String, Value, Ban> =
+        db.query("user", "bans", Some(qp)).await?;
+
+    let ban = result
+        .rows
+        .into_iter()
+        .filter_map(|r| r.doc)
+        .filter(|b| b.is_active(now) && b.covers(ip))
+        .max_by_key(|b| b.expires.unwrap_or(DateTime::<Utc>::MAX_UTC));
+
+    if cache_secs > 0
+    {
+        if let Ok(cached) = serde_json::to_string(&ban)
+        {
+            if let Err(e) = bus.set_key(&key, cached, cache_secs as i32).await
+            {
+                warn!("error caching ban {}: {:?}", key, e);
+            }
+        }
+    }
+
+    Ok(ban)
+}
+
+pub fn thread_cooldown_key(board_code: &str, ip: &IpAddr) -> String
+{
+    format!("cooldown:thread:{}:{}", board_code, ip.to_canonical())
+}
+
+pub fn reply_cooldown_key(board_code: &str, ip: &IpAddr) -> String
+{
+    format!("cooldown:reply:{}:{}", board_code, ip.to_canonical())
+}
+
+pub fn delete_cooldown_key(board_code: &str, ip: &IpAddr) -> String
+{
+    format!("cooldown:delete:{}:{}", board_code, ip.to_canonical())
+}
+
+pub fn rate_key(ip: &IpAddr) -> String
+{
+    format!("rate:{}", ip.to_canonical())
+}
+
+pub fn rate_pass_key(request_id: &str) -> String
+{
+    format!("rate_pass:{}", request_id)
+}
+
+pub fn login_ip_key(ip: &IpAddr) -> String
+{
+    format!("login:ip:{}", ip.to_canonical())
+}
+
+pub fn login_user_key(username: &str) -> String
+{
+    format!("login:user:{}", username)
+}
+
+pub fn post_num_key(board_code: &str) -> String
+{
+    format!("post_num:{}", board_code)
+}
+
+async fn seed_data(db: Database)
+{
+    for n in 1..120
+    {
+        let p = Thread {
+            t: _thread(),
+            _id: "".to_string(),
             _rev: "".to_string(),
             board_code: if n % 2 == 0
             {
@@ -399,15 +2046,24 @@ async fn seed_data(db: Database)
                 "b".to_string()
             },
             thread_num: n,
+            subject: "".to_string(),
             body: PostBody {
                 name: "test".to_string(),
                 comment: "x".to_string(),
                 time: chrono::offset::Utc::now(),
                 email: "x@y.com".to_string(),
+                attachment: None,
             },
             bump_time: chrono::offset::Utc::now(),
             archived: false,
             pinned: false,
+            priority: 0,
+            locked: false,
+            autosage: false,
+            flagged: vec![],
+            hidden: false,
+            ip: None,
+            delete_hash: None,
             comments: None,
         };
         let mut doc = serde_json::to_value(p).unwrap();
@@ -440,9 +2096,14 @@ async fn seed_data(db: Database)
                             comment: "x".to_string(),
                             time: chrono::offset::Utc::now(),
                             email: "x@y.com".to_string(),
+                            attachment: None,
                         },
                         parent_thread_id: thread,
                         archived: false,
+                        flagged: vec![],
+                        hidden: false,
+                        ip: None,
+                        delete_hash: None,
                     };
                     let mut cdoc = serde_json::to_value(c).unwrap();
                     match db.create(&mut cdoc).await
@@ -463,3 +2124,221 @@ async fn seed_data(db: Database)
         }
     }
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const CAPABILITIES: [Capability; 7] = [
+        Capability::DeletePosts,
+        Capability::Ban,
+        Capability::Lock,
+        Capability::Pin,
+        Capability::MoveThreads,
+        Capability::EditFilters,
+        Capability::ManageBoards,
+    ];
+
+    fn scoped(role: Role, boards: &[&str]) -> Grant
+    {
+        Grant {
+            boards: Some(boards.iter().map(|b| b.to_string()).collect()),
+            ..Grant::global(role)
+        }
+    }
+
+    #[test]
+    fn admins_can_do_anything()
+    {
+        for c in CAPABILITIES
+        {
+            assert!(Role::Admin.can(c), "{:?}", c);
+        }
+    }
+
+    #[test]
+    fn mods_can_do_anything_but_manage_boards()
+    {
+        for c in CAPABILITIES
+        {
+            assert_eq!(Role::Mod.can(c), c != Capability::ManageBoards);
+        }
+    }
+
+    #[test]
+    fn jannies_cant_ban()
+    {
+        assert!(!Role::Janny.can(Capability::Ban));
+        assert!(!Grant::global(Role::Janny).allows(Capability::Ban, "g"));
+    }
+
+    #[test]
+    fn jannies_can_only_delete_posts()
+    {
+        for c in CAPABILITIES
+        {
+            assert_eq!(Role::Janny.can(c), c == Capability::DeletePosts);
+        }
+    }
+
+    #[test]
+    fn users_can_do_nothing()
+    {
+        for c in CAPABILITIES
+        {
+            assert!(!Role::User.can(c), "{:?}", c);
+            assert!(!Grant::user().allows(c, "g"), "{:?}", c);
+        }
+    }
+
+    #[test]
+    fn global_grants_cover_every_board()
+    {
+        let grant = Grant::global(Role::Mod);
+        assert!(grant.covers("g"));
+        assert!(grant.covers("v"));
+        assert!(grant.allows(Capability::Ban, "v"));
+        assert!(grant.check(Capability::Lock, "g").is_ok());
+    }
+
+    #[test]
+    fn board_mods_only_act_on_their_boards()
+    {
+        let grant = scoped(Role::Mod, &["g", "tv"]);
+        assert!(grant.covers("g"));
+        assert!(grant.covers("tv"));
+        assert!(!grant.covers("v"));
+        for c in CAPABILITIES
+        {
+            assert!(!grant.allows(c, "v"), "{:?}", c);
+            assert!(matches!(
+                grant.check(c, "v"),
+                Err(DispatchError::Forbidden(f)) if f == c
+            ));
+        }
+        assert!(grant.allows(Capability::Ban, "g"));
+        assert!(!grant.allows(Capability::ManageBoards, "g"));
+    }
+
+    #[test]
+    fn totp_codes_only_work_once()
+    {
+        let mut account = StaffAccount {
+            t: _staff(),
+            _id: staff_doc_id("mod"),
+            _rev: "".to_string(),
+            username: "mod".to_string(),
+            password: "".to_string(),
+            role: Role::Mod,
+            boards: None,
+            totp_secret: None,
+            totp_last_step: None,
+        };
+        assert!(account.verify_totp(""));
+
+        account.enable_totp();
+        let secret = account.totp_secret.clone().unwrap();
+        let bytes = Secret::Encoded(secret).to_bytes().unwrap();
+        let totp = TOTP::new(Algorithm::SHA1, 6, 1, 30, bytes).unwrap();
+        let code = totp.generate_current().unwrap();
+
+        assert!(!account.verify_totp(""));
+        assert!(account.verify_totp(&code));
+        assert!(!account.verify_totp(&code));
+    }
+
+    #[test]
+    fn deletion_secrets_match_only_themselves()
+    {
+        for secret in [
+            DeleteSecret::Password(prehash_secret("hunter2")),
+            DeleteSecret::Token(prehash_secret("0123456789abcdef")),
+        ]
+        {
+            let stored = secret.stored_hash().unwrap();
+            assert!(verify_delete_hash(&stored, secret.hash()));
+            assert!(!verify_delete_hash(&stored, &prehash_secret("nope")));
+        }
+    }
+
+    #[test]
+    fn a_grant_on_no_boards_covers_none()
+    {
+        let grant = scoped(Role::Admin, &[]);
+        assert!(!grant.covers("g"));
+        assert!(!grant.allows(Capability::DeletePosts, "g"));
+    }
+
+    fn ban(range: &str) -> Ban
+    {
+        Ban {
+            t: _ban(),
+            _id: "".to_string(),
+            _rev: "".to_string(),
+            range: range.to_string(),
+            board_code: None,
+            reason: "".to_string(),
+            staff: "".to_string(),
+            time: Utc::now(),
+            expires: None,
+        }
+    }
+
+    fn ip(addr: &str) -> IpAddr
+    {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn v4_mapped_ranges_are_v4_ranges()
+    {
+        assert_eq!(
+            parse_range("::ffff:1.2.3.0/120"),
+            Some((ip("1.2.3.0"), 24))
+        );
+        let b = ban("::ffff:1.2.3.0/120");
+        assert!(b.covers(&ip("1.2.3.4")));
+        assert!(b.covers(&ip("::ffff:1.2.3.4")));
+        assert!(!b.covers(&ip("1.2.4.4")));
+    }
+
+    #[test]
+    fn zero_prefixes_cover_their_whole_family()
+    {
+        let b = ban("0.0.0.0/0");
+        assert!(b.covers(&ip("1.2.3.4")));
+        assert!(b.covers(&ip("255.255.255.255")));
+        assert!(!b.covers(&ip("2001:db8::1")));
+
+        let b = ban("::/0");
+        assert!(b.covers(&ip("2001:db8::1")));
+        assert!(b.covers(&ip("::1")));
+    }
+
+    #[test]
+    fn v6_prefixes_cover_their_range()
+    {
+        let b = ban("2001:db8::/32");
+        assert!(b.covers(&ip("2001:db8::1")));
+        assert!(b.covers(&ip("2001:db8:ffff::1")));
+        assert!(!b.covers(&ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn prefixes_longer_than_the_address_are_refused()
+    {
+        assert_eq!(parse_range("1.2.3.4/33"), None);
+        assert_eq!(parse_range("2001:db8::/129"), None);
+        assert!(!ban("1.2.3.4/33").covers(&ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn v4_ranges_dont_cover_v6_clients()
+    {
+        let b = ban("1.2.3.0/24");
+        assert!(!b.covers(&ip("2001:db8::1")));
+        assert!(!b.covers(&ip("::1.2.3.4")));
+        assert!(b.covers(&ip("::ffff:1.2.3.4")));
+    }
+}
@@ -1,3 +1,13 @@
+use std::{
+    io,
+    net::IpAddr,
+    sync::LazyLock,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
 use config::Config;
 use couch_rs::{
     database::Database,
@@ -5,9 +15,16 @@ use couch_rs::{
     types::{
         find::FindQuery,
         query::QueryParams,
-        view::RawViewCollection,
+        view::{
+            RawViewCollection,
+            ViewCollection,
+        },
     },
 };
+use futures_util::{
+    stream,
+    StreamExt,
+};
 use log::{
     debug,
     error,
@@ -24,48 +41,959 @@ use poem::{
     },
     get,
     handler,
-    http::StatusCode,
+    http::{
+        HeaderMap,
+        StatusCode,
+    },
     listener::TcpListener,
-    middleware::AddData,
+    middleware::Csrf,
+    post,
+    session::{
+        CookieConfig,
+        CookieSession,
+        Session,
+    },
     web::{
+        cookie::CookieKey,
+        sse::{
+            Event,
+            SSE,
+        },
+        CsrfToken,
+        CsrfVerifier,
         Data,
+        Form,
         Json,
+        Multipart,
         Path,
+        Query,
+        Redirect,
+        StaticFileRequest,
     },
+    Addr,
     EndpointExt,
+    FromRequest,
     IntoResponse,
+    Request,
+    RequestBody,
     Response,
     Route,
     Server,
 };
+use serde::{
+    Deserialize,
+    Serialize,
+};
 use serde_json::{
     json,
     Map,
     Value,
 };
 use spriteib_lib::{
-    Comment,
-    DispatchError,
-    PostBody,
-    RedisBus,
-    Thread,
     _comment,
+    _staff,
     _thread,
+    delete_cooldown_key,
+    find_ban,
     get_couch_settings,
     get_redis_settings,
     get_sprite_settings,
+    get_web_settings,
+    hash_secret,
+    login_ip_key,
+    login_user_key,
+    prehash_secret,
+    staff_doc_id,
+    staging_path,
+    verify_secret,
+    Ban,
+    Comment,
+    DeleteSecret,
+    DispatchError,
+    Grant,
+    Message,
+    NewThreadMessage,
+    PostBody,
+    RedisBus,
+    SpriteSettings,
+    StaffAccount,
+    Thread,
+    Upload,
+    WebSettings,
 };
 use tera::Tera;
+use tokio::io::AsyncReadExt;
+use uuid::Uuid;
+
+const STATUS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const STATUS_WAIT: Duration = Duration::from_secs(60);
+const STATUS_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/* checked against when logging in as nobody, to take as long as logging in
+ * as somebody
+ */
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| {
+    hash_secret(&Uuid::new_v4().to_string()).expect("Could not hash")
+});
+
+/* the key settings.toml used to come with. anyone can read it, and so forge
+ * a session with it.
+ */
+const SAMPLE_SESSION_KEY: &str =
+    "change me to a long random string of your own";
 
 fn get_dynamic_settings(db: &Database, board_code: Option<String>) -> bool
 {
     true
 }
 
+/* proxies whose forwarding headers are believed. anyone else can put what
+ * they like in them, so for everyone else the peer address is the client.
+ */
+#[derive(Clone)]
+struct TrustedProxies(Vec<IpAddr>);
+
+struct ClientIp(Option<IpAddr>);
+
+impl<'a> FromRequest<'a> for ClientIp
+{
+    async fn from_request(
+        req: &'a Request,
+        _body: &mut RequestBody,
+    ) -> Result<Self>
+    {
+        let peer = match req.remote_addr().0
+        {
+            Addr::SocketAddr(addr) => addr.ip().to_canonical(),
+            _ => return Ok(ClientIp(None)),
+        };
+        let trusted = req.data::<TrustedProxies>().map_or(&[][..], |t| &t.0);
+        Ok(ClientIp(Some(client_ip(peer, req.headers(), trusted))))
+    }
+}
+
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[IpAddr]) -> IpAddr
+{
+    if !trusted.contains(&peer)
+    {
+        return peer;
+    }
+
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let hops = match header("x-forwarded-for")
+    {
+        Some(hops) => hops,
+        None =>
+        {
+            return header("x-real-ip")
+                .and_then(|v| v.trim().parse::<IpAddr>().ok())
+                .map_or(peer, |ip| ip.to_canonical())
+        }
+    };
+
+    /* each proxy appends the address it heard from, so walk back from the
+     * nearest hop and stop at the first one that isn't ours.
+     */
+    let mut client = peer;
+    for hop in hops.rsplit(',')
+    {
+        match hop.trim().parse::<IpAddr>()
+        {
+            Ok(ip) => client = ip.to_canonical(),
+            Err(_) => break,
+        }
+        if !trusted.contains(&client)
+        {
+            break;
+        }
+    }
+    client
+}
+
+#[derive(Deserialize)]
+struct BoardQuery
+{
+    page: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct BoardEntry
+{
+    id: String,
+    thread: Thread,
+}
+
 #[handler]
-async fn get_board(Path(name): Path<String>) -> String
+async fn get_board(
+    Path(board): Path<String>,
+    Query(query): Query<BoardQuery>,
+    listing_db: Data<&Database>,
+    tpl: Data<&Tera>,
+    sprite_settings: Data<&SpriteSettings>,
+    csrf_token: &CsrfToken,
+) -> poem::error::Result<impl IntoResponse>
+{
+    let page = query.page.unwrap_or(0);
+    let per_page = sprite_settings.board_threads_per_page as u64;
+    let skip = match page.checked_mul(per_page)
+    {
+        Some(skip) => skip,
+        None => return Err(NotFoundError.into()),
+    };
+
+    /* the listing view is keyed on [board_code, pinned, priority,
+     * bump_time], so walking it backwards gives pinned threads first, by
+     * priority, and then everything else by most recent bump. one extra
+     * row is fetched to tell whether there is a next page.
+     */
+    let sk = json!([board, {}]);
+    let ek = json!([board]);
+
+    let qp = QueryParams::default()
+        .start_key(sk)
+        .end_key(ek)
+        .descending(true)
+        .include_docs(true)
+        .skip(skip)
+        .limit(per_page + 1);
+
+    let result: Result<ViewCollection<Value, Value, Thread>, CouchError> =
+        listing_db.query("user", "thread_view", Some(qp)).await;
+
+    match result
+    {
+        Ok(vc) =>
+        {
+            let has_next = vc.rows.len() as u64 > per_page;
+
+            let threads = vc
+                .rows
+                .into_iter()
+                .take(per_page as usize)
+                .filter_map(|r| r.doc)
+                .map(|t| BoardEntry {
+                    // listing documents share the main document's id, plus
+                    // a suffix
+                    id: t._id.strip_suffix("li").unwrap_or(&t._id).to_string(),
+                    thread: t,
+                })
+                .collect::<Vec<BoardEntry>>();
+
+            if threads.is_empty() && page > 0
+            {
+                return Err(NotFoundError.into());
+            }
+
+            let mut ctx = tera::Context::new();
+            ctx.insert("board", &board);
+            ctx.insert("threads", &threads);
+            ctx.insert("page", &page);
+            ctx.insert("has_next", &has_next);
+            ctx.insert("csrf_token", &csrf_token.0);
+
+            let rendered = tpl.render("board/index.tera.html", &ctx).unwrap();
+
+            Ok(Response::builder().body(rendered))
+        }
+        Err(e) =>
+        {
+            error!("{:?}", e);
+            Ok(Response::builder()
+                .status(poem::http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body(()))
+        }
+    }
+}
+
+#[derive(Default)]
+struct NewThreadForm
 {
-    format!("hello: {}", name)
+    csrf_token: String,
+    name: String,
+    email: String,
+    subject: String,
+    comment: String,
+    password: String,
+    upload: Option<Upload>,
+}
+
+/* a file field is written straight to the staging directory, where the
+ * worker picks it up. anything past max_size is cut off; the worker still
+ * sees the file is too large and reports it as such.
+ */
+async fn read_thread_form(
+    mut multipart: Multipart,
+    staged: &std::path::Path,
+    max_size: u64,
+) -> poem::error::Result<NewThreadForm>
+{
+    let mut form = NewThreadForm::default();
+
+    while let Some(field) = multipart.next_field().await?
+    {
+        match field.name()
+        {
+            Some("csrf_token") => form.csrf_token = field.text().await?,
+            Some("name") => form.name = field.text().await?,
+            Some("email") => form.email = field.text().await?,
+            Some("subject") => form.subject = field.text().await?,
+            Some("comment") => form.comment = field.text().await?,
+            Some("password") => form.password = field.text().await?,
+            Some("file") =>
+            {
+                let filename = field.file_name().unwrap_or("").to_string();
+                if filename.is_empty()
+                {
+                    continue;
+                }
+
+                let content_type = field
+                    .content_type()
+                    .unwrap_or("application/octet-stream")
+                    .to_string();
+
+                let mut data = field.into_async_read().take(max_size + 1);
+                stage_upload(&mut data, staged).await.map_err(|e| {
+                    error!("error staging upload {:?}: {:?}", staged, e);
+                    InternalServerError(e)
+                })?;
+
+                form.upload = Some(Upload {
+                    filename,
+                    content_type,
+                });
+            }
+            _ => (),
+        }
+    }
+
+    Ok(form)
+}
+
+async fn stage_upload(
+    data: &mut (impl tokio::io::AsyncRead + Unpin),
+    staged: &std::path::Path,
+) -> io::Result<()>
+{
+    if let Some(parent) = staged.parent()
+    {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let mut file = tokio::fs::File::create(staged).await?;
+    tokio::io::copy(data, &mut file).await?;
+    Ok(())
+}
+
+async fn discard_upload(staged: &std::path::Path)
+{
+    match tokio::fs::remove_file(staged).await
+    {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => error!("error removing upload {:?}: {:?}", staged, e),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[handler]
+async fn post_thread(
+    Path(board): Path<String>,
+    ClientIp(remote_ip): ClientIp,
+    verifier: &CsrfVerifier,
+    multipart: Multipart,
+    db: Data<&Database>,
+    bus: Data<&RedisBus>,
+    tpl: Data<&Tera>,
+    sprite_settings: Data<&SpriteSettings>,
+    web_settings: Data<&WebSettings>,
+    session: &Session,
+) -> poem::error::Result<impl IntoResponse>
+{
+    let request_id = Uuid::new_v4();
+    let staged = staging_path(&sprite_settings.file_dir, &request_id);
+    let result = publish_thread(
+        board,
+        remote_ip,
+        verifier,
+        session,
+        multipart,
+        &db,
+        &bus,
+        &tpl,
+        &sprite_settings,
+        &web_settings,
+        request_id,
+        &staged,
+    )
+    .await;
+
+    // nothing will come for an upload whose request never made it out
+    if result.is_err()
+    {
+        discard_upload(&staged).await;
+    }
+
+    Ok(Json(json!({ "request_id": result? }))
+        .with_status(StatusCode::ACCEPTED))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn publish_thread(
+    board: String,
+    remote_ip: Option<std::net::IpAddr>,
+    verifier: &CsrfVerifier,
+    session: &Session,
+    multipart: Multipart,
+    db: &Database,
+    bus: &RedisBus,
+    tpl: &Tera,
+    sprite_settings: &SpriteSettings,
+    web_settings: &WebSettings,
+    request_id: Uuid,
+    staged: &std::path::Path,
+) -> poem::error::Result<Uuid>
+{
+    let form = read_thread_form(
+        multipart,
+        staged,
+        sprite_settings.post_op_max_file_size as u64,
+    )
+    .await?;
+
+    if !verifier.is_valid(&form.csrf_token)
+    {
+        return Err(Error::from_status(StatusCode::UNAUTHORIZED));
+    }
+
+    let remote_ip = match remote_ip
+    {
+        Some(ip) => ip,
+        None =>
+        {
+            error!("Could not determine remote address for new thread");
+            return Err(Error::from_status(StatusCode::BAD_REQUEST));
+        }
+    };
+
+    /* the worker turns banned posters away too; this is only so they get
+     * told why.
+     */
+    let mut bus = bus.clone();
+    match find_ban(
+        db,
+        &mut bus,
+        &board,
+        &remote_ip,
+        sprite_settings.ban_cache_secs,
+    )
+    .await
+    {
+        Ok(Some(ban)) => return Err(ban_page(tpl, &ban, &remote_ip)),
+        Ok(None) => (),
+        Err(e) => error!("error looking up bans for {}: {:?}", remote_ip, e),
+    }
+
+    /* the worker enforces all posting rules; only reject here what could
+     * never make a valid post.
+     */
+    let comment = form.comment.trim();
+    if comment.is_empty()
+    {
+        return Err(Error::from_string(
+            "comment is required",
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    if comment.chars().count() as i64 > sprite_settings.post_op_max_length
+    {
+        return Err(Error::from_string(
+            "comment is too long",
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let grant = session_grant(db, session, web_settings).await;
+
+    let message = Message::NewThread {
+        data: NewThreadMessage {
+            subject: form.subject.trim().to_string(),
+            body: PostBody {
+                name: form.name.trim().to_string(),
+                comment: comment.to_string(),
+                time: chrono::offset::Utc::now(),
+                email: form.email.trim().to_string(),
+                attachment: None,
+            },
+            upload: form.upload,
+            delete_secret: Some(delete_secret(session, &form.password)),
+        },
+        request_id,
+        remote_ip,
+        grant,
+        board_code: board,
+    };
+
+    let payload = serde_json::to_string(&message).map_err(|e| {
+        error!("error serializing new thread message: {:?}", e);
+        InternalServerError(e)
+    })?;
+
+    match bus.publish("NewThread", &payload).await
+    {
+        Ok(_) => Ok(request_id),
+        Err(e) =>
+        {
+            error!("error publishing new thread message: {:?}", e);
+            Err(Error::from_status(StatusCode::SERVICE_UNAVAILABLE))
+        }
+    }
+}
+
+/* posters who don't give a password to delete their posts with get a
+ * random token, kept in their session, which stands in for one on every
+ * post they make from that browser. the worker does the hashing, once the
+ * post has passed its checks.
+ */
+fn delete_secret(session: &Session, password: &str) -> DeleteSecret
+{
+    if !password.is_empty()
+    {
+        return DeleteSecret::Password(prehash_secret(password));
+    }
+
+    let token = match session.get::<String>("delete_token")
+    {
+        Some(token) => token,
+        None =>
+        {
+            let token = Uuid::new_v4().simple().to_string();
+            session.set("delete_token", &token);
+            token
+        }
+    };
+    DeleteSecret::Token(prehash_secret(&token))
+}
+
+#[derive(Deserialize)]
+struct DeleteForm
+{
+    csrf_token: String,
+    post_id: String,
+    #[serde(default)]
+    password: String,
+}
+
+/* the worker decides whether the password is right; like posting, the
+ * answer only says the request was taken. checking a password is slow, so
+ * each address has to wait cooldown.delete seconds between tries.
+ */
+#[allow(clippy::too_many_arguments)]
+#[handler]
+async fn post_delete(
+    Path(board): Path<String>,
+    Form(form): Form<DeleteForm>,
+    ClientIp(remote_ip): ClientIp,
+    verifier: &CsrfVerifier,
+    session: &Session,
+    bus: Data<&RedisBus>,
+    sprite_settings: Data<&SpriteSettings>,
+) -> poem::error::Result<impl IntoResponse>
+{
+    if !verifier.is_valid(&form.csrf_token)
+    {
+        return Err(Error::from_status(StatusCode::UNAUTHORIZED));
+    }
+
+    let remote_ip = match remote_ip
+    {
+        Some(ip) => ip,
+        None =>
+        {
+            error!("Could not determine remote address for delete");
+            return Err(Error::from_status(StatusCode::BAD_REQUEST));
+        }
+    };
+
+    let mut bus = bus.clone();
+    let cooldown = sprite_settings.delete_cooldown(&board);
+    if cooldown > 0
+    {
+        let key = delete_cooldown_key(&board, &remote_ip);
+        match bus.take_attempt(&key, 1, cooldown).await
+        {
+            Ok(0) => (),
+            Ok(_) =>
+            {
+                return Err(Error::from_status(StatusCode::TOO_MANY_REQUESTS))
+            }
+            Err(e) =>
+            {
+                error!("error taking delete cooldown {}: {:?}", key, e);
+                return Err(Error::from_status(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                ));
+            }
+        }
+    }
+
+    let message = Message::UserDeletePost {
+        post_id: form.post_id.clone(),
+        board_code: board,
+        password_hash: delete_secret(session, &form.password)
+            .hash()
+            .to_string(),
+    };
+
+    let payload = serde_json::to_string(&message).map_err(|e| {
+        error!("error serializing delete message: {:?}", e);
+        InternalServerError(e)
+    })?;
+
+    match bus.publish("UserDeletePost", &payload).await
+    {
+        Ok(_) => Ok(Json(json!({ "post_id": form.post_id }))
+            .with_status(StatusCode::ACCEPTED)),
+        Err(e) =>
+        {
+            error!("error publishing delete message: {:?}", e);
+            Err(Error::from_status(StatusCode::SERVICE_UNAVAILABLE))
+        }
+    }
+}
+
+/* the staff member logged in to the session, unless they logged in longer
+ * ago than a session may last. the cookie's max-age is only a request to
+ * the browser, so the login time is checked here too.
+ */
+fn session_staff(
+    session: &Session,
+    web_settings: &WebSettings,
+) -> Option<String>
+{
+    let since = session.get::<i64>("staff_since")?;
+    let age = chrono::offset::Utc::now().timestamp() - since;
+    if age > web_settings.session_max_age_secs
+    {
+        return None;
+    }
+    session.get::<String>("staff")
+}
+
+/* the grant of the staff member logged in to the session, or a user's
+ * when nobody is. the account is read on every request, so removing it or
+ * changing its role takes effect at once.
+ */
+async fn session_grant(
+    db: &Database,
+    session: &Session,
+    web_settings: &WebSettings,
+) -> Grant
+{
+    let username = match session_staff(session, web_settings)
+    {
+        Some(u) => u,
+        None => return Grant::user(),
+    };
+
+    match db.get::<StaffAccount>(&staff_doc_id(&username)).await
+    {
+        Ok(account) if account.t == _staff() => account.grant(),
+        Ok(_) => Grant::user(),
+        Err(e) if e.is_not_found() => Grant::user(),
+        Err(e) =>
+        {
+            error!("error fetching staff account {}: {:?}", username, e);
+            Grant::user()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginForm
+{
+    csrf_token: String,
+    username: String,
+    password: String,
+    #[serde(default)]
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct LogoutForm
+{
+    csrf_token: String,
+}
+
+fn login_page(
+    tpl: &Tera,
+    csrf_token: &CsrfToken,
+    staff: Option<String>,
+    failed: bool,
+) -> Response
+{
+    let mut ctx = tera::Context::new();
+    ctx.insert("csrf_token", &csrf_token.0);
+    ctx.insert("staff", &staff);
+    ctx.insert("failed", &failed);
+
+    let status = if failed
+    {
+        StatusCode::UNAUTHORIZED
+    }
+    else
+    {
+        StatusCode::OK
+    };
+
+    match tpl.render("staff/login.tera.html", &ctx)
+    {
+        Ok(rendered) => Response::builder()
+            .status(status)
+            .content_type("text/html; charset=utf-8")
+            .body(rendered),
+        Err(e) =>
+        {
+            error!("error rendering login page: {:?}", e);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .finish()
+        }
+    }
+}
+
+#[handler]
+async fn get_login(
+    tpl: Data<&Tera>,
+    web_settings: Data<&WebSettings>,
+    csrf_token: &CsrfToken,
+    session: &Session,
+) -> Response
+{
+    login_page(
+        &tpl,
+        csrf_token,
+        session_staff(session, &web_settings),
+        false,
+    )
+}
+
+/* whether logging in from the address, or as the user, has been tried too
+ * often lately. counted whether or not the attempts succeed.
+ */
+async fn too_many_logins(
+    bus: &RedisBus,
+    web_settings: &WebSettings,
+    ip: &IpAddr,
+    username: &str,
+) -> poem::error::Result<bool>
+{
+    let mut bus = bus.clone();
+    for key in [login_ip_key(ip), login_user_key(username)]
+    {
+        let wait = bus
+            .take_attempt(
+                &key,
+                web_settings.login_max_attempts,
+                web_settings.login_window_secs,
+            )
+            .await
+            .map_err(|e| {
+                error!("error counting login attempts {}: {:?}", key, e);
+                Error::from_status(StatusCode::SERVICE_UNAVAILABLE)
+            })?;
+        if wait > 0
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/* the same answer is given for an unknown account, a wrong password and a
+ * wrong or missing code, so as not to tell which it was. an unknown account
+ * is checked against a made up hash, so it takes as long as any other.
+ */
+#[allow(clippy::too_many_arguments)]
+#[handler]
+async fn post_login(
+    Form(form): Form<LoginForm>,
+    ClientIp(remote_ip): ClientIp,
+    verifier: &CsrfVerifier,
+    csrf_token: &CsrfToken,
+    session: &Session,
+    db: Data<&Database>,
+    bus: Data<&RedisBus>,
+    tpl: Data<&Tera>,
+    web_settings: Data<&WebSettings>,
+) -> poem::error::Result<Response>
+{
+    if !verifier.is_valid(&form.csrf_token)
+    {
+        return Err(Error::from_status(StatusCode::UNAUTHORIZED));
+    }
+
+    let remote_ip = match remote_ip
+    {
+        Some(ip) => ip,
+        None =>
+        {
+            error!("Could not determine remote address for login");
+            return Err(Error::from_status(StatusCode::BAD_REQUEST));
+        }
+    };
+
+    if too_many_logins(&bus, &web_settings, &remote_ip, &form.username).await?
+    {
+        warn!("Too many logins for {} from {}", form.username, remote_ip);
+        return Err(Error::from_status(StatusCode::TOO_MANY_REQUESTS));
+    }
+
+    let account = match db
+        .get::<StaffAccount>(&staff_doc_id(&form.username))
+        .await
+    {
+        Ok(a) if a.t == _staff() => Some(a),
+        Ok(_) => None,
+        Err(e) if e.is_not_found() => None,
+        Err(e) =>
+        {
+            error!("error fetching staff account {}: {:?}", form.username, e);
+            return Err(Error::from_status(StatusCode::SERVICE_UNAVAILABLE));
+        }
+    };
+
+    let password = form.password;
+    let account = tokio::task::spawn_blocking(move || match account
+    {
+        Some(a) => a.verify_password(&password).then_some(a),
+        None =>
+        {
+            verify_secret(&DUMMY_HASH, &password);
+            None
+        }
+    })
+    .await
+    .map_err(InternalServerError)?
+    .and_then(|mut a| a.verify_totp(&form.code).then_some(a));
+
+    match account
+    {
+        Some(mut a) =>
+        {
+            // keep the code from being used again
+            if a.totp_secret.is_some()
+            {
+                if let Err(e) = db.save(&mut a).await
+                {
+                    error!("error saving staff account {}: {:?}", a._id, e);
+                    return Err(Error::from_status(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                    ));
+                }
+            }
+            info!("{} logged in", a.username);
+            session.renew();
+            session.set("staff", &a.username);
+            session.set("staff_since", chrono::offset::Utc::now().timestamp());
+            Ok(Redirect::see_other("/login").into_response())
+        }
+        None =>
+        {
+            warn!("Failed login for {}", form.username);
+            Ok(login_page(&tpl, csrf_token, None, true))
+        }
+    }
+}
+
+#[handler]
+async fn post_logout(
+    Form(form): Form<LogoutForm>,
+    verifier: &CsrfVerifier,
+    session: &Session,
+) -> poem::error::Result<Response>
+{
+    if !verifier.is_valid(&form.csrf_token)
+    {
+        return Err(Error::from_status(StatusCode::UNAUTHORIZED));
+    }
+
+    session.purge();
+    Ok(Redirect::see_other("/login").into_response())
+}
+
+fn ban_page(tpl: &Tera, ban: &Ban, ip: &std::net::IpAddr) -> Error
+{
+    let mut ctx = tera::Context::new();
+    ctx.insert("ban", ban);
+    ctx.insert("ip", &ip.to_string());
+    ctx.insert("expires", &ban.expires.map(|e| e.to_rfc2822()));
+
+    match tpl.render("ban/view.tera.html", &ctx)
+    {
+        Ok(rendered) => Error::from_response(
+            Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .content_type("text/html; charset=utf-8")
+                .body(rendered),
+        ),
+        Err(e) =>
+        {
+            error!("error rendering ban page: {:?}", e);
+            Error::from_status(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+/* feeds are written by the worker as flat files; board feeds live under
+ * board/, the site-wide feed next to them as all.rss and all.atom.
+ */
+#[handler]
+async fn get_board_feed(
+    Path((board, feed)): Path<(String, String)>,
+    req: StaticFileRequest,
+    sprite_settings: Data<&SpriteSettings>,
+) -> poem::error::Result<impl IntoResponse>
+{
+    let ext = feed.trim_start_matches("feed.");
+    let path = std::path::Path::new(&sprite_settings.feed_output_dir)
+        .join("board")
+        .join(format!("{}.{}", board, ext));
+
+    Ok(req.create_response(path, true)?)
+}
+
+#[handler]
+async fn get_feed(
+    Path(feed): Path<String>,
+    req: StaticFileRequest,
+    sprite_settings: Data<&SpriteSettings>,
+) -> poem::error::Result<impl IntoResponse>
+{
+    let ext = feed.trim_start_matches("feed.");
+    let path = std::path::Path::new(&sprite_settings.feed_output_dir)
+        .join(format!("all.{}", ext));
+
+    Ok(req.create_response(path, true)?)
+}
+
+/* stored files and their thumbnails, as laid out by the worker. staged
+ * uploads sit in the same directory, so only these two trees are served.
+ */
+#[handler]
+async fn get_file(
+    Path((tree, prefix, file)): Path<(String, String, String)>,
+    req: StaticFileRequest,
+    sprite_settings: Data<&SpriteSettings>,
+) -> poem::error::Result<impl IntoResponse>
+{
+    let path = std::path::Path::new(&sprite_settings.file_dir)
+        .join(tree)
+        .join(prefix)
+        .join(file);
+
+    Ok(req.create_response(path, true)?)
 }
 
 #[handler]
@@ -85,7 +1013,8 @@ async fn get_thread(
 
     let qp = QueryParams::default().start_key(sk).end_key(ek);
 
-    let result: Result<RawViewCollection<Value, PostBody>, CouchError> =
+    /* the OP row carries the thread's subject alongside its post body */
+    let result: Result<RawViewCollection<Value, Value>, CouchError> =
         db.query("user", "thread_view", Some(qp)).await;
 
     match result
@@ -114,14 +1043,11 @@ async fn get_thread(
                             &vc.rows[1..]
                                 .iter()
                                 .map(|v| v.clone().value)
-                                .collect::<Vec<PostBody>>(),
+                                .collect::<Vec<Value>>(),
                         ),
 
                         // no comments, just pass empty list
-                        None =>
-                        {
-                            ctx.insert("comments", &Vec::<PostBody>::new())
-                        }
+                        None => ctx.insert("comments", &Vec::<Value>::new()),
                     }
 
                     let rendered =
@@ -141,6 +1067,78 @@ async fn get_thread(
     }
 }
 
+/* post requests are handled by the worker, which leaves the outcome under
+ * the request id. the id has to parse as one, so that other keys in Redis
+ * stay out of reach.
+ */
+#[handler]
+async fn get_status(
+    Path(request_id): Path<Uuid>,
+    bus: Data<&RedisBus>,
+) -> poem::error::Result<impl IntoResponse>
+{
+    let key = request_id.to_string();
+
+    let mut bus = bus.clone();
+    match bus.get_status(&key).await
+    {
+        Ok(Some(status)) => match serde_json::from_str::<Value>(&status)
+        {
+            Ok(v) => Ok(Json(v)),
+            Err(e) =>
+            {
+                error!("error reading status of {}: {:?}", key, e);
+                Err(InternalServerError(e))
+            }
+        },
+        Ok(None) => Ok(Json(json!({ "status": "pending" }))),
+        Err(e) =>
+        {
+            error!("error fetching status of {}: {:?}", key, e);
+            Err(Error::from_status(StatusCode::SERVICE_UNAVAILABLE))
+        }
+    }
+}
+
+/* sends a single "status" event once the worker is done with the request.
+ * if that takes too long, "pending" is sent instead and the client's
+ * EventSource will reconnect to keep waiting.
+ */
+#[handler]
+async fn get_status_events(
+    Path(request_id): Path<Uuid>,
+    bus: Data<&RedisBus>,
+) -> poem::error::Result<impl IntoResponse>
+{
+    let key = request_id.to_string();
+
+    let mut bus = bus.clone();
+    let status = stream::once(async move {
+        let started = Instant::now();
+        loop
+        {
+            match bus.get_status(&key).await
+            {
+                Ok(Some(status)) => break status,
+                Ok(None) if started.elapsed() < STATUS_WAIT => (),
+                Ok(None) => break json!({ "status": "pending" }).to_string(),
+                Err(e) =>
+                {
+                    error!("error fetching status of {}: {:?}", key, e);
+                    break json!({ "status": "pending" }).to_string();
+                }
+            }
+
+            tokio::time::sleep(STATUS_POLL_INTERVAL).await;
+        }
+    });
+
+    Ok(
+        SSE::new(status.map(|s| Event::message(s).event_type("status")))
+            .keep_alive(STATUS_KEEP_ALIVE),
+    )
+}
+
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error>
 {
@@ -154,6 +1152,22 @@ async fn main() -> Result<(), std::io::Error>
     let sprite_settings = get_sprite_settings(&s).unwrap();
     let couch_settings = get_couch_settings(&s).unwrap();
     let redis_settings = get_redis_settings(&s).unwrap();
+    let web_settings = get_web_settings(&s).unwrap();
+
+    let session_key = match &web_settings.session_key
+    {
+        None => panic!("SPRITEIB_SESSION_KEY must be set"),
+        Some(k) if k.len() < 32 =>
+        {
+            panic!("the session key must be at least 32 bytes")
+        }
+        Some(k) if k == SAMPLE_SESSION_KEY =>
+        {
+            panic!("the session key must be one of your own")
+        }
+        Some(k) => CookieKey::derive_from(k.as_bytes()),
+    };
+    LazyLock::force(&DUMMY_HASH);
 
     let client = couch_rs::Client::new(
         &couch_settings.host,
@@ -175,6 +1189,7 @@ async fn main() -> Result<(), std::io::Error>
     let mut bus = RedisBus {
         uri: redis_settings.connection_string,
         connection: None,
+        stream_max_length: redis_settings.stream_max_length as usize,
     };
 
     match bus.connect().await
@@ -188,13 +1203,68 @@ async fn main() -> Result<(), std::io::Error>
     let app = Route::new()
         .at(
             "/board/:board<[A-Za-z]+>/:thread<[A-Fa-f0-9]+>/",
-            get(get_thread),
+            get(get_thread).data(db.clone()).data(tera.clone()),
+        )
+        .at(
+            "/board/:board<[A-Za-z]+>",
+            get(get_board)
+                .data(listing_db)
+                .data(tera.clone())
+                .data(sprite_settings.clone()),
         )
-        .with(AddData::new(db))
-        .with(AddData::new(tera))
-        .at("/board/:board<[A-Za-z]+>", get(get_board))
-        .with(AddData::new(listing_db))
-        .with(AddData::new(tera))
+        .at(
+            "/board/:board<[A-Za-z]+>/",
+            post(post_thread)
+                .data(db.clone())
+                .data(bus.clone())
+                .data(tera.clone())
+                .data(sprite_settings.clone())
+                .data(web_settings.clone()),
+        )
+        .at(
+            "/board/:board<[A-Za-z]+>/delete",
+            post(post_delete)
+                .data(bus.clone())
+                .data(sprite_settings.clone()),
+        )
+        .at(
+            "/board/:board<[A-Za-z]+>/:feed<feed\\.(rss|atom)>",
+            get(get_board_feed).data(sprite_settings.clone()),
+        )
+        .at(
+            "/:feed<feed\\.(rss|atom)>",
+            get(get_feed).data(sprite_settings.clone()),
+        )
+        .at(
+            "/files/:tree<src|thumb>/:prefix<[0-9a-f]{2}>/:file<[0-9a-f]+\\.\
+             [A-Za-z0-9]+>",
+            get(get_file).data(sprite_settings.clone()),
+        )
+        .at(
+            "/login",
+            get(get_login)
+                .post(post_login)
+                .data(db)
+                .data(bus.clone())
+                .data(tera)
+                .data(web_settings.clone()),
+        )
+        .at("/logout", post(post_logout))
+        .at("/status/:request_id", get(get_status).data(bus.clone()))
+        .at(
+            "/status/:request_id/events",
+            get(get_status_events).data(bus.clone()),
+        )
+        .data(TrustedProxies(web_settings.trusted_proxies.clone()))
+        .with(Csrf::new())
+        .with(CookieSession::new(
+            CookieConfig::private(session_key)
+                .name("spriteib_session")
+                .secure(web_settings.session_secure)
+                .max_age(Duration::from_secs(
+                    web_settings.session_max_age_secs as u64,
+                )),
+        ))
         .catch_error(|_: NotFoundError| async move {
             Response::builder()
                 .status(StatusCode::NOT_FOUND)
@@ -205,3 +1275,65 @@ async fn main() -> Result<(), std::io::Error>
         .run(app)
         .await
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const PROXY: &str = "10.0.0.1";
+
+    fn ip(addr: &str) -> IpAddr
+    {
+        addr.parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap
+    {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs
+        {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn untrusted_peers_cant_forward()
+    {
+        let h = headers(&[
+            ("x-forwarded-for", "1.2.3.4"),
+            ("x-real-ip", "1.2.3.4"),
+        ]);
+        assert_eq!(client_ip(ip("5.6.7.8"), &h, &[ip(PROXY)]), ip("5.6.7.8"));
+    }
+
+    #[test]
+    fn chained_trusted_proxies_are_walked_back()
+    {
+        let trusted = [ip(PROXY), ip("10.0.0.2")];
+        let h = headers(&[("x-forwarded-for", "6.6.6.6, 1.2.3.4, 10.0.0.2")]);
+        assert_eq!(client_ip(ip(PROXY), &h, &trusted), ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn unparseable_hops_stop_the_walk()
+    {
+        let trusted = [ip(PROXY), ip("10.0.0.2")];
+        let h = headers(&[("x-forwarded-for", "1.2.3.4, bogus")]);
+        assert_eq!(client_ip(ip(PROXY), &h, &trusted), ip(PROXY));
+
+        let h = headers(&[("x-forwarded-for", "bogus, 10.0.0.2")]);
+        assert_eq!(client_ip(ip(PROXY), &h, &trusted), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn real_ip_is_used_without_forwarded_for()
+    {
+        let h = headers(&[("x-real-ip", " ::ffff:1.2.3.4 ")]);
+        assert_eq!(client_ip(ip(PROXY), &h, &[ip(PROXY)]), ip("1.2.3.4"));
+
+        let h = headers(&[("x-real-ip", "bogus")]);
+        assert_eq!(client_ip(ip(PROXY), &h, &[ip(PROXY)]), ip(PROXY));
+    }
+}
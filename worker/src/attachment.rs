@@ -0,0 +1,333 @@
+use std::{
+    fs,
+    io::{
+        self,
+        Cursor,
+    },
+    path::Path,
+};
+
+use image::{
+    codecs::jpeg::JpegEncoder,
+    metadata::Orientation,
+    DynamicImage,
+    ImageDecoder,
+    ImageFormat,
+    ImageReader,
+    Limits,
+};
+use log::{
+    error,
+    info,
+};
+use mime::Mime;
+use sha2::{
+    Digest,
+    Sha256,
+};
+use spriteib_lib::{
+    Attachment,
+    PostStatus,
+    Upload,
+};
+use uuid::Uuid;
+
+use crate::{
+    delete::remove_file,
+    metadata::strip_metadata,
+};
+
+// formats we can decode, and so give dimensions and a thumbnail
+const THUMBNAILED: [ImageFormat; 4] = [
+    ImageFormat::Jpeg,
+    ImageFormat::Png,
+    ImageFormat::Gif,
+    ImageFormat::WebP,
+];
+
+// quality of JPEGs that have to be encoded again to bake in their rotation
+const JPEG_QUALITY: u8 = 90;
+
+/* what an upload is held to on the board it is posted to. dimensions and
+ * decoded size are checked from the image's header, before it is decoded.
+ */
+pub struct UploadRules
+{
+    pub max_size: u64,
+    pub allowed: Vec<String>,
+    pub max_dimension: u32,
+    pub max_decode_bytes: u64,
+    pub thumb_size: u32,
+}
+
+/* move a staged upload into the file directory and thumbnail it. files are
+ * stored as src/<xx>/<hash>.<ext> and thumb/<xx>/<hash>.<ext>, <xx> being
+ * the first two characters of the hash, so the same file posted twice is
+ * only stored once. the staged file itself is left alone.
+ *
+ * the type of the file is told from its contents alone, and it must be one
+ * of those allowed; what the client claimed it was counts for nothing.
+ * images have their metadata stripped before they are stored, so the hash
+ * is that of the file as served.
+ */
+pub fn store_upload(
+    file_dir: &Path,
+    staged: &Path,
+    upload: &Upload,
+    rules: &UploadRules,
+) -> Result<Attachment, PostStatus>
+{
+    let size = match fs::metadata(staged)
+    {
+        Ok(m) => m.len(),
+        Err(e) =>
+        {
+            error!("error reading upload {:?}: {:?}", staged, e);
+            return Err(PostStatus::FailedProcessing);
+        }
+    };
+
+    if size > rules.max_size
+    {
+        return Err(PostStatus::LargeFile);
+    }
+
+    let data = fs::read(staged).map_err(|e| {
+        error!("error reading upload {:?}: {:?}", staged, e);
+        PostStatus::FailedProcessing
+    })?;
+
+    let kind = match infer::get(&data)
+    {
+        Some(k) if is_allowed(k.mime_type(), &rules.allowed) => k,
+        Some(k) =>
+        {
+            info!(
+                "Rejected {} ({}, sent as {})",
+                upload.filename,
+                k.mime_type(),
+                upload.content_type
+            );
+            return Err(PostStatus::BadMIME);
+        }
+        None =>
+        {
+            info!(
+                "Rejected {} (unknown, sent as {})",
+                upload.filename, upload.content_type
+            );
+            return Err(PostStatus::BadMIME);
+        }
+    };
+
+    let mime = kind.mime_type().to_string();
+    let ext = kind.extension();
+    let format =
+        ImageFormat::from_mime_type(&mime).filter(|f| THUMBNAILED.contains(f));
+
+    let (data, img) = match format
+    {
+        Some(f) =>
+        {
+            let (data, img) = clean_image(data, f, rules)?;
+            (data, Some(img))
+        }
+        None => (data, None),
+    };
+
+    /* a rotated image is encoded again, and can come out larger */
+    if data.len() as u64 > rules.max_size
+    {
+        info!("Rejected {} (too large once rotated)", upload.filename);
+        return Err(PostStatus::LargeFile);
+    }
+
+    let hash = hex::encode(Sha256::digest(&data));
+    let file = format!("{}/{}.{}", &hash[..2], hash, ext);
+    let src = file_dir.join("src").join(&file);
+    let fresh = store_file(&src, &data)?;
+
+    let mut attachment = Attachment {
+        filename: upload.filename.clone(),
+        hash,
+        size: data.len() as u64,
+        width: None,
+        height: None,
+        mime,
+        file,
+        thumb: None,
+    };
+
+    if let Some(img) = img
+    {
+        attachment.width = Some(img.width());
+        attachment.height = Some(img.height());
+        let thumb =
+            thumbnail(file_dir, &attachment.hash, &img, rules.thumb_size);
+        // don't leave a file behind that no post will have
+        if thumb.is_err() && fresh
+        {
+            remove_file(&src);
+        }
+        attachment.thumb = Some(thumb?);
+    }
+
+    Ok(attachment)
+}
+
+/* decode an image within the limits, and return it along with the file to
+ * store in its place. a rotated image is turned the right way up and
+ * encoded again, which leaves its metadata behind; any other just has its
+ * metadata cut out.
+ */
+fn clean_image(
+    data: Vec<u8>,
+    format: ImageFormat,
+    rules: &UploadRules,
+) -> Result<(Vec<u8>, DynamicImage), PostStatus>
+{
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(rules.max_dimension);
+    limits.max_image_height = Some(rules.max_dimension);
+    limits.max_alloc = Some(rules.max_decode_bytes);
+
+    let mut reader = ImageReader::with_format(Cursor::new(&data), format);
+    reader.limits(limits);
+
+    let decoded = reader.into_decoder().and_then(|mut d| {
+        let orientation = d.orientation()?;
+        if d.total_bytes() > rules.max_decode_bytes
+        {
+            return Err(image::ImageError::Limits(
+                image::error::LimitError::from_kind(
+                    image::error::LimitErrorKind::InsufficientMemory,
+                ),
+            ));
+        }
+        Ok((orientation, DynamicImage::from_decoder(d)?))
+    });
+
+    let (orientation, mut img) = decoded.map_err(|e| {
+        info!("Rejected {:?} image: {:?}", format, e);
+        PostStatus::FailedProcessing
+    })?;
+
+    if orientation == Orientation::NoTransforms
+    {
+        let stripped = strip_metadata(format, data).map_err(|e| {
+            error!("error stripping {:?} image: {:?}", format, e);
+            PostStatus::FailedProcessing
+        })?;
+        return Ok((stripped, img));
+    }
+
+    img.apply_orientation(orientation);
+
+    let mut out = Cursor::new(Vec::new());
+    let encoded = match format
+    {
+        ImageFormat::Jpeg => img.write_with_encoder(
+            JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY),
+        ),
+        f => img.write_to(&mut out, f),
+    };
+
+    encoded.map_err(|e| {
+        error!("error encoding rotated {:?} image: {:?}", format, e);
+        PostStatus::FailedProcessing
+    })?;
+
+    Ok((out.into_inner(), img))
+}
+
+/* thumbnails keep transparency as PNG, anything else is a JPEG */
+fn thumbnail(
+    file_dir: &Path,
+    hash: &str,
+    img: &DynamicImage,
+    thumb_size: u32,
+) -> Result<String, PostStatus>
+{
+    let t = img.thumbnail(thumb_size, thumb_size);
+    let (t, format, ext) = if t.color().has_alpha()
+    {
+        (
+            DynamicImage::ImageRgba8(t.to_rgba8()),
+            ImageFormat::Png,
+            "png",
+        )
+    }
+    else
+    {
+        (
+            DynamicImage::ImageRgb8(t.to_rgb8()),
+            ImageFormat::Jpeg,
+            "jpg",
+        )
+    };
+
+    let mut data = Cursor::new(Vec::new());
+    t.write_to(&mut data, format).map_err(|e| {
+        error!("error encoding thumbnail of {}: {:?}", hash, e);
+        PostStatus::FailedProcessing
+    })?;
+
+    let thumb = format!("{}/{}.{}", &hash[..2], hash, ext);
+    store_file(&file_dir.join("thumb").join(&thumb), data.get_ref())?;
+
+    Ok(thumb)
+}
+
+/* whether the MIME type is on the list. a subtype of * on the list allows
+ * every subtype of its type.
+ */
+fn is_allowed(mime: &str, allowed: &[String]) -> bool
+{
+    let mime = match mime.parse::<Mime>()
+    {
+        Ok(m) => m,
+        Err(_) => return false,
+    };
+
+    allowed
+        .iter()
+        .filter_map(|a| a.parse::<Mime>().ok())
+        .any(|a| {
+            a.type_() == mime.type_()
+                && (a.subtype() == mime::STAR || a.subtype() == mime.subtype())
+        })
+}
+
+/* returns whether the file was written, rather than already there */
+fn store_file(path: &Path, contents: &[u8]) -> Result<bool, PostStatus>
+{
+    // named after the content, so it's already there in full or not at all
+    if path.exists()
+    {
+        return Ok(false);
+    }
+
+    write_file(path, contents).map_err(|e| {
+        error!("error storing {:?}: {:?}", path, e);
+        PostStatus::FailedProcessing
+    })?;
+    Ok(true)
+}
+
+fn write_file(path: &Path, contents: &[u8]) -> io::Result<()>
+{
+    if let Some(parent) = path.parent()
+    {
+        fs::create_dir_all(parent)?;
+    }
+
+    // named uniquely so that writers of the same path never share it
+    let tmp = path.with_file_name(format!(".{}.tmp", Uuid::new_v4()));
+    let written =
+        fs::write(&tmp, contents).and_then(|_| fs::rename(&tmp, path));
+    if written.is_err()
+    {
+        let _ = fs::remove_file(&tmp);
+    }
+    written
+}
@@ -0,0 +1,544 @@
+use std::{
+    fs,
+    io,
+    net::IpAddr,
+    path::Path,
+};
+
+use chrono::Utc;
+use couch_rs::{
+    database::Database,
+    error::CouchError,
+    http::StatusCode,
+    types::{
+        query::QueryParams,
+        view::{
+            RawViewCollection,
+            ViewCollection,
+        },
+    },
+};
+use log::{
+    error,
+    info,
+    warn,
+};
+use serde_json::Value;
+use spriteib_lib::{
+    _ban,
+    _comment,
+    _thread,
+    ban_key,
+    verify_delete_hash,
+    Attachment,
+    Ban,
+    BanPoster,
+    Comment,
+    DeleteMode,
+    DispatchError,
+    Grant,
+    RedisBus,
+    Thread,
+};
+
+/* delete a thread or a comment, whichever the id turns out to be */
+#[allow(clippy::too_many_arguments)]
+pub async fn delete_post(
+    db: &Database,
+    listing_db: &Database,
+    redis_bus: &mut RedisBus,
+    file_dir: &Path,
+    post_id: &str,
+    board_code: &str,
+    mode: DeleteMode,
+    ban: Option<&BanPoster>,
+    grant: &Grant,
+) -> Result<(), DispatchError>
+{
+    let doc = match db.get::<Value>(post_id).await
+    {
+        Ok(doc) => doc,
+        Err(err) if err.is_not_found() =>
+        {
+            warn!("No post {} on /{}/ to delete", post_id, board_code);
+            return Ok(());
+        }
+        Err(err) =>
+        {
+            error!("error fetching post {}: {:?}", post_id, err);
+            return Err(DispatchError::DeleteFailed);
+        }
+    };
+
+    if doc["t"] == _thread()
+    {
+        delete_thread(
+            db, listing_db, redis_bus, file_dir, post_id, board_code, mode,
+            ban, grant,
+        )
+        .await
+    }
+    else if doc["t"] == _comment()
+    {
+        let c = serde_json::from_value::<Comment>(doc).map_err(|err| {
+            error!("error reading comment {}: {:?}", post_id, err);
+            DispatchError::DeleteFailed
+        })?;
+        delete_comment(
+            db, listing_db, redis_bus, file_dir, c, board_code, mode, ban,
+            grant,
+        )
+        .await
+    }
+    else
+    {
+        warn!("No post {} on /{}/ to delete", post_id, board_code);
+        Ok(())
+    }
+}
+
+/* delete a post for whoever made it, if the password or token they give
+ * matches the one it was made with and it is recent enough. posts deleted
+ * this way are hidden, so staff can still see what was there.
+ */
+#[allow(clippy::too_many_arguments)]
+pub async fn user_delete_post(
+    db: &Database,
+    listing_db: &Database,
+    redis_bus: &mut RedisBus,
+    file_dir: &Path,
+    post_id: &str,
+    board_code: &str,
+    password_hash: &str,
+    window_secs: i64,
+) -> Result<(), DispatchError>
+{
+    let doc = match db.get::<Value>(post_id).await
+    {
+        Ok(doc) => doc,
+        Err(err) if err.is_not_found() =>
+        {
+            warn!("No post {} on /{}/ to delete", post_id, board_code);
+            return Ok(());
+        }
+        Err(err) =>
+        {
+            error!("error fetching post {}: {:?}", post_id, err);
+            return Err(DispatchError::DeleteFailed);
+        }
+    };
+
+    let post = if doc["t"] == _thread()
+    {
+        serde_json::from_value::<Thread>(doc)
+            .map(|t| (t.board_code, t.hidden, t.delete_hash, t.body.time))
+    }
+    else if doc["t"] == _comment()
+    {
+        serde_json::from_value::<Comment>(doc)
+            .map(|c| (c.board_code, c.hidden, c.delete_hash, c.body.time))
+    }
+    else
+    {
+        warn!("No post {} on /{}/ to delete", post_id, board_code);
+        return Ok(());
+    };
+
+    let (bc, hidden, hash, time) = post.map_err(|err| {
+        error!("error reading post {}: {:?}", post_id, err);
+        DispatchError::DeleteFailed
+    })?;
+
+    let hash = match hash
+    {
+        Some(h) if bc == board_code && !hidden => h,
+        _ =>
+        {
+            warn!("Post {} on /{}/ can't be deleted", post_id, board_code);
+            return Ok(());
+        }
+    };
+
+    if (Utc::now() - time).num_seconds() > window_secs
+    {
+        info!("Post {} is too old for its poster to delete", post_id);
+        return Ok(());
+    }
+
+    let password_hash = password_hash.to_string();
+    let matches = tokio::task::spawn_blocking(move || {
+        verify_delete_hash(&hash, &password_hash)
+    })
+    .await
+    .map_err(|err| {
+        error!("error checking password for {}: {:?}", post_id, err);
+        DispatchError::DeleteFailed
+    })?;
+
+    if !matches
+    {
+        info!("Wrong password to delete {}", post_id);
+        return Ok(());
+    }
+
+    delete_post(
+        db,
+        listing_db,
+        redis_bus,
+        file_dir,
+        post_id,
+        board_code,
+        DeleteMode::Hide,
+        None,
+        &Grant::user(),
+    )
+    .await
+}
+
+/* delete a thread and every comment it has. it leaves the board first, so
+ * that a failure part way through leaves nothing half deleted on show, and
+ * the thread itself goes last so that running it again picks up whatever
+ * is left.
+ */
+#[allow(clippy::too_many_arguments)]
+pub async fn delete_thread(
+    db: &Database,
+    listing_db: &Database,
+    redis_bus: &mut RedisBus,
+    file_dir: &Path,
+    thread_id: &str,
+    board_code: &str,
+    mode: DeleteMode,
+    ban: Option<&BanPoster>,
+    grant: &Grant,
+) -> Result<(), DispatchError>
+{
+    let mut t = match db.get::<Thread>(thread_id).await
+    {
+        Ok(t) if t.t == _thread() && t.board_code == board_code => t,
+        Ok(_) =>
+        {
+            warn!("No thread {} on /{}/ to delete", thread_id, board_code);
+            return Ok(());
+        }
+        Err(err) if err.is_not_found() =>
+        {
+            warn!("No thread {} on /{}/ to delete", thread_id, board_code);
+            return Ok(());
+        }
+        Err(err) =>
+        {
+            error!("error fetching thread {}: {:?}", thread_id, err);
+            return Err(DispatchError::DeleteFailed);
+        }
+    };
+
+    if let Some(ban) = ban
+    {
+        ban_poster(db, redis_bus, thread_id, board_code, t.ip, ban, grant)
+            .await?;
+    }
+
+    let listing_id = format!("{}li", thread_id);
+    match listing_db.get::<Thread>(&listing_id).await
+    {
+        Ok(lt) =>
+        {
+            if !listing_db.remove(&lt).await
+            {
+                error!("error removing listing thread {}", listing_id);
+                return Err(DispatchError::DeleteFailed);
+            }
+        }
+        Err(err) if err.is_not_found() => (),
+        Err(err) =>
+        {
+            error!("error fetching listing thread {}: {:?}", listing_id, err);
+            return Err(DispatchError::DeleteFailed);
+        }
+    }
+
+    let comments = thread_comments(db, thread_id).await.map_err(|err| {
+        error!("error fetching comments for {}: {:?}", thread_id, err);
+        DispatchError::DeleteFailed
+    })?;
+
+    for c in comments
+    {
+        delete_doc(db, file_dir, c, mode).await?;
+    }
+
+    match mode
+    {
+        DeleteMode::Hide if t.hidden => (),
+        DeleteMode::Hide =>
+        {
+            t.hidden = true;
+            if let Err(err) = db.save(&mut t).await
+            {
+                error!("error hiding thread {}: {:?}", thread_id, err);
+                return Err(DispatchError::DeleteFailed);
+            }
+        }
+        DeleteMode::Remove =>
+        {
+            if !db.remove(&t).await
+            {
+                error!("error removing thread {}", thread_id);
+                return Err(DispatchError::DeleteFailed);
+            }
+            remove_files(db, file_dir, t.body.attachment.as_ref()).await;
+        }
+    }
+
+    info!("Thread {} deleted ({:?})", thread_id, mode);
+    Ok(())
+}
+
+/* delete a comment, taking it out of its thread's preview on the board
+ * first
+ */
+#[allow(clippy::too_many_arguments)]
+async fn delete_comment(
+    db: &Database,
+    listing_db: &Database,
+    redis_bus: &mut RedisBus,
+    file_dir: &Path,
+    c: Comment,
+    board_code: &str,
+    mode: DeleteMode,
+    ban: Option<&BanPoster>,
+    grant: &Grant,
+) -> Result<(), DispatchError>
+{
+    if c.board_code != board_code
+    {
+        warn!("No comment {} on /{}/ to delete", c._id, board_code);
+        return Ok(());
+    }
+
+    if let Some(ban) = ban
+    {
+        ban_poster(db, redis_bus, &c._id, board_code, c.ip, ban, grant)
+            .await?;
+    }
+
+    let listing_id = format!("{}li", c.parent_thread_id);
+    match listing_db.get::<Thread>(&listing_id).await
+    {
+        Ok(mut lt) =>
+        {
+            let mut preview = lt.comments.take().unwrap_or_default();
+            let before = preview.len();
+            preview.retain(|p| p._id != c._id);
+            let changed = preview.len() != before;
+            lt.comments = Some(preview);
+
+            if changed
+            {
+                if let Err(err) = listing_db.save(&mut lt).await
+                {
+                    error!(
+                        "error updating listing thread {}: {:?}",
+                        listing_id, err
+                    );
+                    return Err(DispatchError::DeleteFailed);
+                }
+            }
+        }
+        Err(err) if err.is_not_found() => (),
+        Err(err) =>
+        {
+            error!("error fetching listing thread {}: {:?}", listing_id, err);
+            return Err(DispatchError::DeleteFailed);
+        }
+    }
+
+    let id = c._id.clone();
+    delete_doc(db, file_dir, c, mode).await?;
+    info!("Comment {} deleted ({:?})", id, mode);
+    Ok(())
+}
+
+async fn delete_doc(
+    db: &Database,
+    file_dir: &Path,
+    mut c: Comment,
+    mode: DeleteMode,
+) -> Result<(), DispatchError>
+{
+    match mode
+    {
+        DeleteMode::Hide if c.hidden => Ok(()),
+        DeleteMode::Hide =>
+        {
+            c.hidden = true;
+            db.save(&mut c).await.map(|_| ()).map_err(|err| {
+                error!("error hiding comment {}: {:?}", c._id, err);
+                DispatchError::DeleteFailed
+            })
+        }
+        DeleteMode::Remove =>
+        {
+            if !db.remove(&c).await
+            {
+                error!("error removing comment {}", c._id);
+                return Err(DispatchError::DeleteFailed);
+            }
+            remove_files(db, file_dir, c.body.attachment.as_ref()).await;
+            Ok(())
+        }
+    }
+}
+
+async fn thread_comments(
+    db: &Database,
+    thread_id: &str,
+) -> Result<Vec<Comment>, CouchError>
+{
+    let qp = QueryParams::default()
+        .key(thread_id.to_string())
+        .include_docs(true);
+
+    let result: ViewCollection<String, Value, Comment> =
+        db.query("user", "thread_comments", Some(qp)).await?;
+
+    Ok(result.rows.into_iter().filter_map(|r| r.doc).collect())
+}
+
+/* ban the address a post was made from. the ban is named after the post,
+ * so deleting it again doesn't ban twice. posts made before addresses were
+ * kept can't be banned this way. the address' cached ban lookups are
+ * dropped, so the ban holds from the next post on.
+ */
+async fn ban_poster(
+    db: &Database,
+    redis_bus: &mut RedisBus,
+    post_id: &str,
+    board_code: &str,
+    ip: Option<IpAddr>,
+    ban: &BanPoster,
+    grant: &Grant,
+) -> Result<(), DispatchError>
+{
+    let ip = match ip
+    {
+        Some(ip) => ip,
+        None =>
+        {
+            warn!("No address on record for {}, not banning", post_id);
+            return Ok(());
+        }
+    };
+
+    let time = Utc::now();
+    let mut b = Ban {
+        t: _ban(),
+        _id: format!("ban-{}", post_id),
+        _rev: "".to_string(),
+        range: ip.to_string(),
+        board_code: if ban.all_boards
+        {
+            None
+        }
+        else
+        {
+            Some(board_code.to_string())
+        },
+        reason: ban.reason.clone(),
+        staff: grant.staff.clone().unwrap_or_default(),
+        time,
+        expires: match ban.hours
+        {
+            h if h > 0 => Some(time + chrono::Duration::hours(h)),
+            _ => None,
+        },
+    };
+
+    match db.save(&mut b).await
+    {
+        Ok(_) => info!("Poster of {} banned as {}", post_id, b._id),
+        Err(err) if err.status() == Some(StatusCode::CONFLICT) =>
+        {
+            info!("Poster of {} already banned", post_id)
+        }
+        Err(err) =>
+        {
+            error!("error banning poster of {}: {:?}", post_id, err);
+            return Err(DispatchError::DeleteFailed);
+        }
+    }
+
+    /* a ban on one board has one cached lookup to drop. as a pattern, the
+     * "*" board matches lookups on every board, but finding them means
+     * scanning the whole keyspace, which only global bans pay for.
+     */
+    let (cached, dropped) = match ban.all_boards
+    {
+        true =>
+        {
+            let pattern = ban_key("*", &ip);
+            let dropped = redis_bus.delete_keys(&pattern).await;
+            (pattern, dropped)
+        }
+        false =>
+        {
+            let key = ban_key(board_code, &ip);
+            let dropped = redis_bus.delete_key(&key).await;
+            (key, dropped)
+        }
+    };
+    if let Err(e) = dropped
+    {
+        warn!("error dropping cached bans {}: {:?}", cached, e);
+    }
+    Ok(())
+}
+
+/* remove a deleted or rejected post's file and thumbnail, unless some
+ * other post has the same file. failures are only logged, since the post is
+ * already gone.
+ */
+pub async fn remove_files(
+    db: &Database,
+    file_dir: &Path,
+    attachment: Option<&Attachment>,
+)
+{
+    let a = match attachment
+    {
+        Some(a) => a,
+        None => return,
+    };
+
+    let qp = QueryParams::default().key(a.hash.clone()).limit(1);
+    let result: Result<RawViewCollection<String, Value>, CouchError> =
+        db.query("user", "file_refs", Some(qp)).await;
+
+    match result
+    {
+        Ok(vc) if vc.rows.is_empty() => (),
+        Ok(_) => return,
+        Err(err) =>
+        {
+            error!("error looking up file {}: {:?}", a.hash, err);
+            return;
+        }
+    }
+
+    remove_file(&file_dir.join("src").join(&a.file));
+    if let Some(thumb) = &a.thumb
+    {
+        remove_file(&file_dir.join("thumb").join(thumb));
+    }
+}
+
+pub fn remove_file(path: &Path)
+{
+    match fs::remove_file(path)
+    {
+        Ok(_) => info!("Removed {:?}", path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => error!("error removing {:?}: {:?}", path, e),
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    fs,
+    io,
+    path::Path,
+};
+
+use atom_syndication::{
+    EntryBuilder,
+    FeedBuilder,
+    LinkBuilder,
+    PersonBuilder,
+};
+use chrono::Utc;
+use rss::{
+    ChannelBuilder,
+    GuidBuilder,
+    ItemBuilder,
+};
+use spriteib_lib::Thread;
+use uuid::Uuid;
+
+pub enum FeedKind
+{
+    Rss,
+    Atom,
+}
+
+impl FeedKind
+{
+    pub fn extension(&self) -> &'static str
+    {
+        match self
+        {
+            FeedKind::Rss => "rss",
+            FeedKind::Atom => "atom",
+        }
+    }
+}
+
+/* feed metadata, shared by both formats */
+pub struct FeedInfo<'a>
+{
+    pub title: &'a str,
+    pub description: &'a str,
+    pub link: &'a str,
+    pub base_url: &'a str,
+}
+
+fn thread_link(base_url: &str, t: &Thread) -> String
+{
+    // threads in the listing db carry the main document's id plus a suffix
+    let id = t._id.strip_suffix("li").unwrap_or(&t._id);
+    format!("{}/board/{}/{}/", base_url, t.board_code, id)
+}
+
+fn thread_title(t: &Thread) -> String
+{
+    if t.subject.is_empty()
+    {
+        format!("/{}/ No. {}", t.board_code, t.thread_num)
+    }
+    else
+    {
+        format!("/{}/ {}", t.board_code, t.subject)
+    }
+}
+
+pub fn render_rss(info: &FeedInfo, threads: &[Thread]) -> String
+{
+    let items = threads
+        .iter()
+        .map(|t| {
+            let link = thread_link(info.base_url, t);
+            ItemBuilder::default()
+                .title(Some(thread_title(t)))
+                .link(Some(link.clone()))
+                .description(Some(t.body.comment.clone()))
+                .guid(Some(GuidBuilder::default().value(link).build()))
+                .pub_date(Some(t.body.time.to_rfc2822()))
+                .build()
+        })
+        .collect::<Vec<_>>();
+
+    ChannelBuilder::default()
+        .title(info.title)
+        .link(info.link)
+        .description(info.description)
+        .last_build_date(Some(Utc::now().to_rfc2822()))
+        .items(items)
+        .build()
+        .to_string()
+}
+
+pub fn render_atom(info: &FeedInfo, threads: &[Thread]) -> String
+{
+    let entries = threads
+        .iter()
+        .map(|t| {
+            let link = thread_link(info.base_url, t);
+            EntryBuilder::default()
+                .title(thread_title(t))
+                .id(link.clone())
+                .updated(t.bump_time)
+                .published(Some(t.body.time.into()))
+                .authors(vec![PersonBuilder::default()
+                    .name(t.body.name.clone())
+                    .build()])
+                .links(vec![LinkBuilder::default().href(link).build()])
+                .summary(Some(t.body.comment.clone().into()))
+                .build()
+        })
+        .collect::<Vec<_>>();
+
+    // an empty feed was last updated whenever it was generated
+    let updated = threads
+        .iter()
+        .map(|t| t.bump_time)
+        .max()
+        .unwrap_or_else(Utc::now);
+
+    FeedBuilder::default()
+        .title(info.title)
+        .id(info.link)
+        .subtitle(Some(info.description.into()))
+        .links(vec![LinkBuilder::default()
+            .href(info.link)
+            .rel("alternate")
+            .build()])
+        .updated(updated)
+        .entries(entries)
+        .build()
+        .to_string()
+}
+
+/* write through a temporary file so that web never serves a partially
+ * written feed.
+ */
+pub fn write_feed(path: &Path, contents: &str) -> io::Result<()>
+{
+    if let Some(parent) = path.parent()
+    {
+        fs::create_dir_all(parent)?;
+    }
+
+    // named uniquely so that writers of the same path never share it
+    let tmp = path.with_file_name(format!(".{}.tmp", Uuid::new_v4()));
+    let written =
+        fs::write(&tmp, contents).and_then(|_| fs::rename(&tmp, path));
+    if written.is_err()
+    {
+        let _ = fs::remove_file(&tmp);
+    }
+    written
+}
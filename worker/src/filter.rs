@@ -0,0 +1,267 @@
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use couch_rs::{
+    database::Database,
+    error::CouchError,
+    types::{
+        query::QueryParams,
+        view::ViewCollection,
+    },
+};
+use log::{
+    info,
+    warn,
+};
+use regex::{
+    NoExpand,
+    Regex,
+    RegexBuilder,
+};
+use serde_json::Value;
+use spriteib_lib::{
+    Filter,
+    FilterAction,
+    FilterField,
+    PostBody,
+    PostStatus,
+};
+
+struct Rule
+{
+    id: String,
+    board_code: Option<String>,
+    field: FilterField,
+    regex: Regex,
+    action: FilterAction,
+    replacement: String,
+    // literal filters put their replacement in as it is, $ and all
+    literal: bool,
+}
+
+impl Rule
+{
+    fn replace(&self, text: &str) -> String
+    {
+        let replaced = match self.literal
+        {
+            true => self.regex.replace_all(text, NoExpand(&self.replacement)),
+            false => self.regex.replace_all(text, self.replacement.as_str()),
+        };
+        replaced.into_owned()
+    }
+}
+
+/* the filters in CouchDB, compiled and kept for `reload` before being read
+ * again, so that changes to them are picked up without a restart.
+ */
+pub struct Filters
+{
+    reload: Duration,
+    loaded: RwLock<Option<(Instant, Arc<Vec<Rule>>)>>,
+}
+
+/* what the filters made of a post. any rejections are the statuses it
+ * fails with; otherwise `flags` are the filters that want it looked at.
+ */
+#[derive(Default)]
+pub struct Filtered
+{
+    pub rejected: Vec<PostStatus>,
+    pub flags: Vec<String>,
+}
+
+impl Filters
+{
+    pub fn new(reload: Duration) -> Filters
+    {
+        Filters {
+            reload,
+            loaded: RwLock::new(None),
+        }
+    }
+
+    async fn rules(&self, db: &Database)
+        -> Result<Arc<Vec<Rule>>, CouchError>
+    {
+        if let Ok(loaded) = self.loaded.read()
+        {
+            if let Some((at, rules)) = loaded.as_ref()
+            {
+                if at.elapsed() < self.reload
+                {
+                    return Ok(rules.clone());
+                }
+            }
+        }
+
+        let qp = QueryParams::default().include_docs(true);
+        let result: ViewCollection<Value, Value, Filter> =
+            db.query("user", "filters", Some(qp)).await?;
+
+        let rules = Arc::new(
+            result
+                .rows
+                .into_iter()
+                .filter_map(|r| r.doc)
+                .filter_map(compile)
+                .collect::<Vec<Rule>>(),
+        );
+
+        if let Ok(mut loaded) = self.loaded.write()
+        {
+            *loaded = Some((Instant::now(), rules.clone()));
+        }
+
+        info!("Loaded {} filters", rules.len());
+        Ok(rules)
+    }
+
+    /* run the board's filters over a post, replacing text in place */
+    pub async fn apply(
+        &self,
+        db: &Database,
+        board_code: &str,
+        mut subject: Option<&mut String>,
+        pb: &mut PostBody,
+    ) -> Result<Filtered, CouchError>
+    {
+        let rules = self.rules(db).await?;
+        let mut filtered = Filtered::default();
+
+        let on_board = rules.iter().filter(|r| {
+            r.board_code.as_deref().is_none_or(|bc| bc == board_code)
+        });
+
+        for rule in on_board
+        {
+            let text = match rule.field
+            {
+                FilterField::Comment => &mut pb.comment,
+                FilterField::Name => &mut pb.name,
+                FilterField::Email => &mut pb.email,
+                FilterField::Subject => match subject.as_deref_mut()
+                {
+                    Some(s) => s,
+                    None => continue,
+                },
+            };
+
+            if !rule.regex.is_match(text)
+            {
+                continue;
+            }
+
+            match rule.action
+            {
+                FilterAction::Reject =>
+                {
+                    let status = rule.field.rejection();
+                    info!("Filter {} rejected post", rule.id);
+                    if !filtered.rejected.contains(&status)
+                    {
+                        filtered.rejected.push(status);
+                    }
+                }
+                FilterAction::Replace => *text = rule.replace(text),
+                FilterAction::Flag =>
+                {
+                    info!("Filter {} flagged post", rule.id);
+                    filtered.flags.push(rule.id.clone());
+                }
+            }
+        }
+
+        Ok(filtered)
+    }
+}
+
+fn compile(f: Filter) -> Option<Rule>
+{
+    let pattern = match f.regex
+    {
+        true => f.pattern.clone(),
+        false => regex::escape(&f.pattern),
+    };
+
+    // literal filters don't care about case
+    match RegexBuilder::new(&pattern)
+        .case_insensitive(!f.regex)
+        .build()
+    {
+        Ok(regex) => Some(Rule {
+            id: f._id,
+            board_code: f.board_code,
+            field: f.field,
+            regex,
+            action: f.action,
+            replacement: f.replacement,
+            literal: !f.regex,
+        }),
+        Err(e) =>
+        {
+            warn!("Skipping filter {}: {}", f._id, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use spriteib_lib::_filter;
+
+    use super::*;
+
+    fn replacer(pattern: &str, regex: bool, replacement: &str) -> Rule
+    {
+        compile(Filter {
+            t: _filter(),
+            _id: "filter".to_string(),
+            _rev: "".to_string(),
+            board_code: None,
+            field: FilterField::Comment,
+            pattern: pattern.to_string(),
+            regex,
+            action: FilterAction::Replace,
+            replacement: replacement.to_string(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn literal_replacements_dont_expand()
+    {
+        let rule = replacer("price", false, "$1 or $name");
+        assert_eq!(rule.replace("the price"), "the $1 or $name");
+    }
+
+    #[test]
+    fn only_regex_replacements_expand()
+    {
+        let rule = replacer("(a+)b", true, "<$1>");
+        assert_eq!(rule.replace("aab ab"), "<aa> <a>");
+
+        let rule = replacer("(a+)b", false, "<$1>");
+        assert_eq!(rule.replace("aab (a+)b"), "aab <$1>");
+    }
+
+    #[test]
+    fn literal_patterns_ignore_case()
+    {
+        let rule = replacer("Spoon", false, "fork");
+        assert!(rule.regex.is_match("SPOON"));
+        assert_eq!(rule.replace("spoon SPOON"), "fork fork");
+
+        let rule = replacer("Spoon", true, "fork");
+        assert_eq!(rule.replace("spoon Spoon"), "spoon fork");
+    }
+}
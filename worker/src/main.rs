@@ -1,45 +1,180 @@
+mod attachment;
+mod delete;
+mod feed;
+mod filter;
+mod metadata;
+
 use std::{
     collections::HashMap,
+    fs,
+    io,
     net::IpAddr,
+    path::{
+        Path,
+        PathBuf,
+    },
     sync::Arc,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
+use attachment::{
+    store_upload,
+    UploadRules,
+};
+use chrono::{
+    DateTime,
+    Utc,
+};
 use config::Config;
 use couch_rs::{
     database::Database,
-    types::view::{
-        CouchFunc,
-        CouchViews,
+    error::CouchError,
+    http::StatusCode,
+    types::{
+        query::QueryParams,
+        view::{
+            CouchFunc,
+            CouchViews,
+            RawViewCollection,
+            ViewCollection,
+        },
     },
 };
-use futures_util::StreamExt as _;
+use delete::{
+    delete_post,
+    delete_thread,
+    remove_files,
+    user_delete_post,
+};
+use feed::{
+    render_atom,
+    render_rss,
+    write_feed,
+    FeedInfo,
+    FeedKind,
+};
+use filter::Filters;
 use log::{
     debug,
     error,
     info,
     warn,
 };
+use serde_json::{
+    json,
+    Value,
+};
 use spriteib_lib::{
+    _ban,
+    _board,
+    _comment,
+    _filter,
+    _staff,
+    _thread,
+    board_doc_id,
+    find_ban,
+    get_couch_settings,
+    get_redis_settings,
+    get_retry_settings,
+    get_sprite_settings,
+    parse_range,
+    reply_cooldown_key,
+    staff_doc_id,
+    staging_path,
+    thread_cooldown_key,
+    Ban,
+    BanPoster,
+    Board,
+    BusError,
+    BusMessage,
+    Capability,
+    Comment,
+    DeadLetter,
+    DeleteMode,
+    DeleteSecret,
     DispatchError,
+    Filter,
+    FilterAction,
+    FilterField,
+    Grant,
     Message,
     PostBody,
     PostStatus,
     RedisBus,
+    RetrySettings,
     Role,
-    Thread,
-    _thread,
-    get_couch_settings,
-    get_redis_settings,
-    get_sprite_settings,
     SpriteSettings,
+    StaffAccount,
+    Thread,
+    Upload,
 };
 use uuid::Uuid;
 
+/* most messages read from the streams at once, and how long a read waits
+ * for new ones before giving idle messages a chance to be reclaimed.
+ */
+const READ_COUNT: usize = 10;
+const READ_BLOCK_MS: usize = 5000;
+
+/* most messages handled at once. nothing more is read until one of them is
+ * done.
+ */
+const MAX_IN_FLIGHT: usize = 32;
+
+/* how long a worker may hold on to a post request before another worker is
+ * allowed to take it over.
+ */
+const REQUEST_CLAIM_TTL: i32 = 300;
+
+/* number of most recent comments kept on a thread's listing document, for
+ * previews on the board index.
+ */
+const LISTING_PREVIEW_COMMENTS: usize = 5;
+
+/* versions of the views in each database's design document. bump one
+ * whenever its views change, and the worker replaces the design document
+ * the next time it starts.
+ */
+const VIEWS_VERSION: i64 = 1;
+const LISTING_VIEWS_VERSION: i64 = 1;
+
+async fn install_views(db: &Database, views: CouchViews, version: i64)
+{
+    let current = db.get::<Value>("_design/user").await.ok();
+    if current
+        .as_ref()
+        .and_then(|d| d.get("version"))
+        .and_then(Value::as_i64)
+        == Some(version)
+    {
+        return;
+    }
+
+    let mut doc = Value::from(views);
+    doc["version"] = json!(version);
+    if let Some(rev) = current.as_ref().and_then(|d| d.get("_rev"))
+    {
+        doc["_rev"] = rev.clone();
+    }
+    db.create_view("user", doc)
+        .await
+        .expect("Could not create view");
+    info!(
+        "Installed version {} of the views in {}",
+        version,
+        db.name()
+    );
+}
+
 async fn dispatch_message(
     message: &Message,
     db: &Database,
     listing_db: &Database,
     post_settings: &SpriteSettings,
+    filters: &Filters,
     redis_bus: &mut RedisBus,
 ) -> Result<(), DispatchError>
 {
@@ -50,242 +185,2771 @@ async fn dispatch_message(
             request_id,
             remote_ip,
             board_code,
-            role,
+            grant,
         } =>
         {
             debug!("Thread dispatch");
-            new_thread(
+            let token = Uuid::new_v4().to_string();
+            if !claim_request(redis_bus, request_id, &token).await?
+            {
+                discard_upload(post_settings, request_id);
+                return Ok(());
+            }
+
+            let result = new_thread(
                 db,
                 listing_db,
                 post_settings,
+                filters,
                 redis_bus,
+                data.subject.clone(),
                 data.body.clone(),
+                data.upload.as_ref(),
+                data.delete_secret.as_ref(),
                 request_id,
                 remote_ip,
                 board_code,
-                role,
+                grant,
             )
-            .await
+            .await;
+
+            finish_request(
+                redis_bus,
+                post_settings,
+                request_id,
+                &token,
+                &result,
+            )
+            .await;
+            result
         }
         Message::NewComment {
             data,
             request_id,
             remote_ip,
             board_code,
-            role,
+            grant,
         } =>
         {
-            debug!("new comment");
-            Ok(())
+            debug!("Comment dispatch");
+            let token = Uuid::new_v4().to_string();
+            if !claim_request(redis_bus, request_id, &token).await?
+            {
+                discard_upload(post_settings, request_id);
+                return Ok(());
+            }
+
+            let result = new_comment(
+                db,
+                listing_db,
+                post_settings,
+                filters,
+                redis_bus,
+                &data.parent_thread_id,
+                data.body.clone(),
+                data.upload.as_ref(),
+                data.delete_secret.as_ref(),
+                request_id,
+                remote_ip,
+                board_code,
+                grant,
+            )
+            .await;
+
+            finish_request(
+                redis_bus,
+                post_settings,
+                request_id,
+                &token,
+                &result,
+            )
+            .await;
+            result
         }
         Message::PruneThreads {
             all_boards,
             board_code,
         } =>
         {
-            debug!("prune threads");
-            Ok(())
+            debug!("Prune dispatch");
+            prune_threads(
+                db,
+                listing_db,
+                post_settings,
+                *all_boards,
+                board_code.as_deref(),
+            )
+            .await
         }
         Message::PublishRss {
             all_boards,
             board_code,
         } =>
         {
-            debug!("publish rss");
-            Ok(())
+            debug!("Feed dispatch");
+            publish_feeds(
+                listing_db,
+                post_settings,
+                *all_boards,
+                board_code.as_deref(),
+            )
+            .await
+        }
+        Message::LockThread {
+            thread_id,
+            board_code,
+            locked,
+            grant,
+        } =>
+        {
+            debug!("Thread lock dispatch");
+            permit(grant, Capability::Lock, board_code)?;
+            lock_thread(db, listing_db, thread_id, board_code, *locked).await
+        }
+        Message::LockBoard {
+            board_code,
+            locked,
+            grant,
+        } =>
+        {
+            debug!("Board lock dispatch");
+            permit(grant, Capability::Lock, board_code)?;
+            lock_board(db, board_code, *locked).await
+        }
+        Message::SetPinned {
+            thread_id,
+            board_code,
+            pinned,
+            priority,
+            grant,
+        } =>
+        {
+            debug!("Thread pin dispatch");
+            permit(grant, Capability::Pin, board_code)?;
+            set_pinned(
+                db, listing_db, redis_bus, thread_id, board_code, *pinned,
+                *priority,
+            )
+            .await
+        }
+        Message::DeletePost {
+            post_id,
+            board_code,
+            mode,
+            ban,
+            grant,
+        } =>
+        {
+            debug!("Post delete dispatch");
+            permit_delete(grant, board_code, ban.as_ref())?;
+            delete_post(
+                db,
+                listing_db,
+                redis_bus,
+                Path::new(&post_settings.file_dir),
+                post_id,
+                board_code,
+                *mode,
+                ban.as_ref(),
+                grant,
+            )
+            .await?;
+            publish_board_feed(redis_bus, board_code).await
+        }
+        Message::DeleteThread {
+            thread_id,
+            board_code,
+            mode,
+            ban,
+            grant,
+        } =>
+        {
+            debug!("Thread delete dispatch");
+            permit_delete(grant, board_code, ban.as_ref())?;
+            delete_thread(
+                db,
+                listing_db,
+                redis_bus,
+                Path::new(&post_settings.file_dir),
+                thread_id,
+                board_code,
+                *mode,
+                ban.as_ref(),
+                grant,
+            )
+            .await?;
+            publish_board_feed(redis_bus, board_code).await
+        }
+        Message::UserDeletePost {
+            post_id,
+            board_code,
+            password_hash,
+        } =>
+        {
+            debug!("User post delete dispatch");
+            user_delete_post(
+                db,
+                listing_db,
+                redis_bus,
+                Path::new(&post_settings.file_dir),
+                post_id,
+                board_code,
+                password_hash,
+                post_settings.post_delete_window_secs,
+            )
+            .await?;
+            publish_board_feed(redis_bus, board_code).await
         }
     }
 }
 
-async fn new_thread(
+/* staff who can lock a board post through locks and rate limits on it */
+fn is_staff(grant: &Grant, board_code: &str) -> bool
+{
+    grant.allows(Capability::Lock, board_code)
+}
+
+/* deleting needs DeletePosts on the board, and banning the poster as well
+ * needs Ban there too, or on every board for a ban on all of them
+ */
+fn permit_delete(
+    grant: &Grant,
+    board_code: &str,
+    ban: Option<&BanPoster>,
+) -> Result<(), DispatchError>
+{
+    permit(grant, Capability::DeletePosts, board_code)?;
+    match ban
+    {
+        Some(b) if b.all_boards && grant.boards.is_some() =>
+        {
+            warn!("{:?} may not ban on all boards", grant);
+            Err(DispatchError::Forbidden(Capability::Ban))
+        }
+        Some(_) => permit(grant, Capability::Ban, board_code),
+        None => Ok(()),
+    }
+}
+
+/* refresh a board's feeds, so deleted posts drop out of them */
+async fn publish_board_feed(
+    redis_bus: &mut RedisBus,
+    board_code: &str,
+) -> Result<(), DispatchError>
+{
+    let publish_rss = serde_json::to_string(&Message::PublishRss {
+        all_boards: false,
+        board_code: Some(board_code.to_string()),
+    })
+    .map_err(|_| DispatchError::DeleteFailed)?;
+
+    redis_bus
+        .publish("PublishRss", &publish_rss)
+        .await
+        .map_err(|e| {
+            error!("failed to send feed message after delete: {:?}", e);
+            DispatchError::DeleteFailed
+        })
+}
+
+fn permit(
+    grant: &Grant,
+    capability: Capability,
+    board_code: &str,
+) -> Result<(), DispatchError>
+{
+    grant.check(capability, board_code).inspect_err(|_| {
+        warn!(
+            "{:?} may not use {:?} on /{}/",
+            grant, capability, board_code
+        )
+    })
+}
+
+/* lock or unlock a thread in the main db, then its listing document so
+ * the board shows it. an archived thread has no listing document.
+ */
+async fn lock_thread(
     db: &Database,
     listing_db: &Database,
-    post_settings: &SpriteSettings,
-    redis_bus: &mut RedisBus,
-    pb: PostBody,
-    rid: &Uuid,
-    rip: &IpAddr,
+    thread_id: &str,
     board_code: &str,
-    role: &Role,
+    locked: bool,
 ) -> Result<(), DispatchError>
 {
-    let mut errors = Vec::<PostStatus>::new();
+    let mut t = match db.get::<Thread>(thread_id).await
+    {
+        Ok(t) if t.t == _thread() && t.board_code == board_code => t,
+        Ok(_) =>
+        {
+            warn!("No thread {} on /{}/ to lock", thread_id, board_code);
+            return Ok(());
+        }
+        Err(err) if err.is_not_found() =>
+        {
+            warn!("No thread {} on /{}/ to lock", thread_id, board_code);
+            return Ok(());
+        }
+        Err(err) =>
+        {
+            error!("error fetching thread {}: {:?}", thread_id, err);
+            return Err(DispatchError::LockFailed);
+        }
+    };
 
-    if pb.comment.chars().count() as i64 > post_settings.post_op_max_length
+    if t.locked != locked
     {
-        info!("Comment exceeded allowed length");
-        errors.push(PostStatus::LargeComment);
+        t.locked = locked;
+        if let Err(err) = db.save(&mut t).await
+        {
+            error!("error locking thread {}: {:?}", thread_id, err);
+            return Err(DispatchError::LockFailed);
+        }
     }
 
-    let mut nope = false;
-    if errors.is_empty()
+    let listing_id = format!("{}li", thread_id);
+    match listing_db.get::<Thread>(&listing_id).await
     {
-        let time = pb.time;
-        let mut p = Thread {
-            t: _thread(),
-            _id: "".to_string(),
-            _rev: "".to_string(),
-            board_code: board_code.to_string(),
-            thread_num: 20,
-            body: pb,
-            bump_time: time,
-            archived: false,
-            pinned: false,
-            comments: None,
-        };
-
-        let mut doc = serde_json::to_value(&p).unwrap();
-        match db.create(&mut doc).await
+        Ok(mut lt) if lt.locked != locked =>
         {
-            Ok(doc) =>
-            {
-                info!("Thread (main) created");
-                p._id = doc.id + "li";
-                let mut listing_doc = serde_json::to_value(&p).unwrap();
-                match listing_db.create(&mut listing_doc).await
-                {
-                    Ok(_) =>
-                    {
-                        info!("Thread (listing) created");
-                    }
-                    Err(err) =>
-                    {
-                        nope = true;
-                        error!(
-                            "error creating document {}: {:?}",
-                            listing_doc, err
-                        );
-                        errors.push(PostStatus::FailedProcessing);
-                    }
-                }
-            }
-            Err(err) =>
+            lt.locked = locked;
+            if let Err(err) = listing_db.save(&mut lt).await
             {
-                nope = true;
-                error!("error creating document {}: {:?}", doc, err);
-                errors.push(PostStatus::FailedProcessing);
+                error!(
+                    "error locking listing thread {}: {:?}",
+                    listing_id, err
+                );
+                return Err(DispatchError::LockFailed);
             }
-        };
+        }
+        Ok(_) => (),
+        Err(err) if err.is_not_found() => (),
+        Err(err) =>
+        {
+            error!("error fetching listing thread {}: {:?}", listing_id, err);
+            return Err(DispatchError::LockFailed);
+        }
     }
 
-    if nope
-    {
-        return Err(DispatchError::NewThreadFailed);
-    }
+    info!(
+        "Thread {} {}",
+        thread_id,
+        if locked { "locked" } else { "unlocked" }
+    );
+    Ok(())
+}
 
-    let mut status_message_map: HashMap<&str, String> = HashMap::new();
-    let mut expiry = 86400_i32;
-    match errors.len()
+/* pin or unpin a thread, main db first and then the listing document the
+ * board is ordered by. a thread that is unpinned counts towards the board's
+ * thread limit again, so the board is pruned after.
+ */
+async fn set_pinned(
+    db: &Database,
+    listing_db: &Database,
+    redis_bus: &mut RedisBus,
+    thread_id: &str,
+    board_code: &str,
+    pinned: bool,
+    priority: i32,
+) -> Result<(), DispatchError>
+{
+    let priority = if pinned { priority } else { 0 };
+    let mut t = match db.get::<Thread>(thread_id).await
     {
-        0 =>
+        Ok(t) if t.t == _thread() && t.board_code == board_code => t,
+        Ok(_) =>
         {
-            status_message_map.insert("status", "ok".to_string());
+            warn!("No thread {} on /{}/ to pin", thread_id, board_code);
+            return Ok(());
         }
-        _ =>
+        Err(err) if err.is_not_found() =>
         {
-            status_message_map.insert("status", "error".to_string());
-            let err_str = errors
-                .into_iter()
-                .map(|e| e.to_string())
-                .collect::<Vec<String>>()
-                .join(", ")
-                .to_string();
-
-            status_message_map.insert("errors", err_str);
-            expiry = 604800;
+            warn!("No thread {} on /{}/ to pin", thread_id, board_code);
+            return Ok(());
+        }
+        Err(err) =>
+        {
+            error!("error fetching thread {}: {:?}", thread_id, err);
+            return Err(DispatchError::PinFailed);
         }
     };
 
-    let status_json = serde_json::to_string(&status_message_map);
-    match status_json
+    if t.archived
     {
-        Ok(val) => match redis_bus
-            .set_status(rid.to_string(), val, expiry)
-            .await
-        {
-            Ok(_) =>
-            {
-                let prune_msg =
-                    serde_json::to_string(&Message::PruneThreads {
-                        all_boards: false,
-                        board_code: Some(board_code.to_string()),
-                    })
-                    .map_err(|e| DispatchError::NewThreadCreatedWithError)?;
+        warn!("Thread {} is archived and can't be pinned", thread_id);
+        return Ok(());
+    }
 
-                let publish_rss =
-                    serde_json::to_string(&Message::PublishRss {
-                        all_boards: false,
-                        board_code: Some(board_code.to_string()),
-                    })
-                    .map_err(|x| DispatchError::NewThreadCreatedWithError)?;
+    if t.pinned != pinned || t.priority != priority
+    {
+        t.pinned = pinned;
+        t.priority = priority;
+        if let Err(err) = db.save(&mut t).await
+        {
+            error!("error pinning thread {}: {:?}", thread_id, err);
+            return Err(DispatchError::PinFailed);
+        }
+    }
 
-                match redis_bus
-                    .publish("PruneThreads", &prune_msg)
-                    .await
-                    .and(redis_bus.publish("PublishRss", &publish_rss).await)
-                {
-                    Ok(_) => Ok(()),
-                    Err(e) =>
-                    {
-                        error!(
-                            "failed to send refresh messages after creathon: \
-                             {:?}",
-                            e
-                        );
-                        Err(DispatchError::NewThreadCreatedWithError)
-                    }
-                }
-            }
-            Err(e) =>
+    let listing_id = format!("{}li", thread_id);
+    match listing_db.get::<Thread>(&listing_id).await
+    {
+        Ok(mut lt) if lt.pinned != pinned || lt.priority != priority =>
+        {
+            lt.pinned = pinned;
+            lt.priority = priority;
+            if let Err(err) = listing_db.save(&mut lt).await
             {
-                error!("error setting post status: {:?}", e);
-                Err(DispatchError::NewThreadCreatedWithError)
+                error!(
+                    "error pinning listing thread {}: {:?}",
+                    listing_id, err
+                );
+                return Err(DispatchError::PinFailed);
             }
-        },
-        Err(e) =>
+        }
+        Ok(_) => (),
+        Err(err) if err.is_not_found() => (),
+        Err(err) =>
         {
-            error!("error serializing post status: {:?}", e);
-            Err(DispatchError::NewThreadCreatedWithError)
+            error!("error fetching listing thread {}: {:?}", listing_id, err);
+            return Err(DispatchError::PinFailed);
         }
     }
-}
-
-#[tokio::main(flavor = "multi_thread", worker_threads = 5)]
-async fn main() -> Result<(), std::io::Error>
-{
-    env_logger::init();
-    let s = Config::builder()
-        .add_source(config::File::with_name("settings"))
-        .build()
-        .unwrap();
 
-    const channels: &[&str] =
-        &["NewThread", "NewComment", "PruneThreads", "PublishRss"];
+    info!(
+        "Thread {} {}",
+        thread_id,
+        if pinned { "pinned" } else { "unpinned" }
+    );
 
-    let sprite_settings = get_sprite_settings(&s).unwrap();
-    let couch_settings = get_couch_settings(&s).unwrap();
-    let redis_settings = get_redis_settings(&s).unwrap();
+    if pinned
+    {
+        return Ok(());
+    }
 
-    let client = couch_rs::Client::new(
-        &couch_settings.host,
-        &couch_settings.username,
-        &couch_settings.password,
-    )
-    .expect("Could not create couchdb client");
+    let prune_msg = serde_json::to_string(&Message::PruneThreads {
+        all_boards: false,
+        board_code: Some(board_code.to_string()),
+    })
+    .map_err(|_| DispatchError::PinFailed)?;
 
-    let db = client
-        .db(&couch_settings.db_listing)
+    redis_bus
+        .publish("PruneThreads", &prune_msg)
+        .await
+        .map_err(|e| {
+            error!("failed to send prune message after unpin: {:?}", e);
+            DispatchError::PinFailed
+        })
+}
+
+async fn lock_board(
+    db: &Database,
+    board_code: &str,
+    locked: bool,
+) -> Result<(), DispatchError>
+{
+    let id = board_doc_id(board_code);
+    let mut board = match db.get::<Board>(&id).await
+    {
+        Ok(b) => b,
+        Err(err) if err.is_not_found() => Board {
+            t: _board(),
+            _id: id.clone(),
+            _rev: "".to_string(),
+            board_code: board_code.to_string(),
+            locked: false,
+        },
+        Err(err) =>
+        {
+            error!("error fetching board {}: {:?}", board_code, err);
+            return Err(DispatchError::LockFailed);
+        }
+    };
+
+    if board.locked != locked
+    {
+        board.locked = locked;
+        if let Err(err) = db.save(&mut board).await
+        {
+            error!("error locking board {}: {:?}", board_code, err);
+            return Err(DispatchError::LockFailed);
+        }
+    }
+
+    info!(
+        "Board /{}/ {}",
+        board_code,
+        if locked { "locked" } else { "unlocked" }
+    );
+    Ok(())
+}
+
+async fn board_locked(
+    db: &Database,
+    board_code: &str,
+) -> Result<bool, CouchError>
+{
+    match db.get::<Board>(&board_doc_id(board_code)).await
+    {
+        Ok(b) => Ok(b.locked),
+        Err(err) if err.is_not_found() => Ok(false),
+        Err(err) =>
+        {
+            error!("error fetching board {}: {:?}", board_code, err);
+            Err(err)
+        }
+    }
+}
+
+/* make sure each post request is processed by one worker at a time, and
+ * only until it has a status. returns false if the request was already
+ * processed, in which case its stored status stands.
+ */
+async fn claim_request(
+    redis_bus: &mut RedisBus,
+    rid: &Uuid,
+    token: &str,
+) -> Result<bool, DispatchError>
+{
+    match redis_bus.get_status(&rid.to_string()).await
+    {
+        Ok(Some(status)) =>
+        {
+            info!("Request {} already processed: {}", rid, status);
+            return Ok(false);
+        }
+        Ok(None) => (),
+        Err(e) =>
+        {
+            error!("error reading status of {}: {:?}", rid, e);
+            return Err(DispatchError::RequestClaimFailed);
+        }
+    }
+
+    match redis_bus
+        .claim_request(&rid.to_string(), token, REQUEST_CLAIM_TTL)
+        .await
+    {
+        Ok(true) => Ok(true),
+        Ok(false) =>
+        {
+            info!("Request {} is being processed elsewhere", rid);
+            Err(DispatchError::RequestInProgress)
+        }
+        Err(e) =>
+        {
+            error!("error claiming request {}: {:?}", rid, e);
+            Err(DispatchError::RequestClaimFailed)
+        }
+    }
+}
+
+/* let a failed request be picked up again straight away, rather than once
+ * its claim runs out. a request that went through no longer needs its
+ * upload, whereas one that failed will want it on the next attempt.
+ */
+async fn finish_request(
+    redis_bus: &mut RedisBus,
+    post_settings: &SpriteSettings,
+    rid: &Uuid,
+    token: &str,
+    result: &Result<(), DispatchError>,
+)
+{
+    if result.is_ok()
+    {
+        discard_upload(post_settings, rid);
+    }
+    else if let Err(e) =
+        redis_bus.release_request(&rid.to_string(), token).await
+    {
+        error!("error releasing request {}: {:?}", rid, e);
+    }
+}
+
+/* store the request's upload, if it came with one. decoding and
+ * thumbnailing are left to the blocking pool.
+ */
+async fn attach_upload(
+    post_settings: &SpriteSettings,
+    rid: &Uuid,
+    upload: Option<&Upload>,
+    max_size: i64,
+    board_code: &str,
+    pb: &mut PostBody,
+) -> Result<(), PostStatus>
+{
+    let upload = match upload
+    {
+        Some(u) => u.clone(),
+        None => return Ok(()),
+    };
+
+    let file_dir = PathBuf::from(&post_settings.file_dir);
+    let staged = staging_path(&post_settings.file_dir, rid);
+    let rules = UploadRules {
+        max_size: max_size as u64,
+        allowed: post_settings.file_types(board_code).to_vec(),
+        max_dimension: post_settings.file_max_dimension as u32,
+        max_decode_bytes: post_settings.file_max_decode_bytes as u64,
+        thumb_size: post_settings.file_thumb_size as u32,
+    };
+
+    let stored = tokio::task::spawn_blocking(move || {
+        store_upload(&file_dir, &staged, &upload, &rules)
+    })
+    .await;
+
+    match stored
+    {
+        Ok(Ok(a)) =>
+        {
+            info!("Stored {} as {}", a.filename, a.file);
+            pb.attachment = Some(a);
+            Ok(())
+        }
+        Ok(Err(status)) => Err(status),
+        Err(e) =>
+        {
+            error!("error storing upload for {}: {:?}", rid, e);
+            Err(PostStatus::FailedProcessing)
+        }
+    }
+}
+
+/* what a post keeps to be deleted by its poster with. it is only worked
+ * out once the post has passed its checks, as passwords are slow to hash.
+ */
+async fn delete_hash(
+    rid: &Uuid,
+    delete_secret: Option<&DeleteSecret>,
+    err: DispatchError,
+) -> Result<Option<String>, DispatchError>
+{
+    let secret = match delete_secret
+    {
+        Some(s) => s.clone(),
+        None => return Ok(None),
+    };
+
+    match tokio::task::spawn_blocking(move || secret.stored_hash()).await
+    {
+        Ok(Ok(hash)) => Ok(Some(hash)),
+        Ok(Err(e)) =>
+        {
+            error!("error hashing deletion password for {}: {:?}", rid, e);
+            Err(err)
+        }
+        Err(e) =>
+        {
+            error!("error hashing deletion password for {}: {:?}", rid, e);
+            Err(err)
+        }
+    }
+}
+
+fn discard_upload(post_settings: &SpriteSettings, rid: &Uuid)
+{
+    let staged = staging_path(&post_settings.file_dir, rid);
+    match fs::remove_file(&staged)
+    {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => error!("error removing upload {:?}: {:?}", staged, e),
+    }
+}
+
+async fn new_thread(
+    db: &Database,
+    listing_db: &Database,
+    post_settings: &SpriteSettings,
+    filters: &Filters,
+    redis_bus: &mut RedisBus,
+    mut subject: String,
+    mut pb: PostBody,
+    upload: Option<&Upload>,
+    delete_secret: Option<&DeleteSecret>,
+    rid: &Uuid,
+    rip: &IpAddr,
+    board_code: &str,
+    grant: &Grant,
+) -> Result<(), DispatchError>
+{
+    // filters go first, since replacements can change the lengths
+    let filtered = filters
+        .apply(db, board_code, Some(&mut subject), &mut pb)
+        .await
+        .map_err(|err| {
+            error!("error loading filters: {:?}", err);
+            DispatchError::NewThreadFailed
+        })?;
+    let mut errors = filtered.rejected;
+
+    if subject.chars().count() as i64
+        > post_settings.post_op_max_subject_length
+    {
+        info!("Subject exceeded allowed length");
+        errors.push(PostStatus::LargeSubject);
+    }
+
+    if pb.comment.chars().count() as i64 > post_settings.post_op_max_length
+    {
+        info!("Comment exceeded allowed length");
+        errors.push(PostStatus::LargeComment);
+    }
+
+    if is_banned(db, post_settings, redis_bus, board_code, rip)
+        .await
+        .map_err(|_| DispatchError::NewThreadFailed)?
+    {
+        errors.push(PostStatus::BannedIp);
+    }
+
+    if !is_staff(grant, board_code)
+        && board_locked(db, board_code)
+            .await
+            .map_err(|_| DispatchError::NewThreadFailed)?
+    {
+        info!("/{}/ is locked", board_code);
+        errors.push(PostStatus::BoardLocked);
+    }
+
+    /* the thread's documents are named after the request, so a request
+     * that is run again finds whatever it already wrote and carries on from
+     * there instead of posting the thread twice.
+     */
+    let thread_id = rid.simple().to_string();
+    let existing = match db.get::<Thread>(&thread_id).await
+    {
+        Ok(t) =>
+        {
+            info!("Thread (main) already created, resuming");
+            errors.clear();
+            Some(t)
+        }
+        Err(err) if err.is_not_found() => None,
+        Err(err) =>
+        {
+            error!("error fetching thread {}: {:?}", thread_id, err);
+            return Err(DispatchError::NewThreadFailed);
+        }
+    };
+
+    let mut wait = None;
+    if errors.is_empty() && existing.is_none()
+    {
+        if let Some(w) = too_fast(
+            redis_bus,
+            post_settings,
+            rid,
+            rip,
+            is_staff(grant, board_code),
+            &thread_cooldown_key(board_code, rip),
+            post_settings.thread_cooldown(board_code),
+        )
+        .await
+        .map_err(|_| DispatchError::NewThreadFailed)?
+        {
+            wait = Some(w);
+            errors.push(PostStatus::TooFast);
+        }
+        else if let Err(status) = attach_upload(
+            post_settings,
+            rid,
+            upload,
+            post_settings.post_op_max_file_size,
+            board_code,
+            &mut pb,
+        )
+        .await
+        {
+            errors.push(status);
+        }
+        else if is_repost(db, post_settings, board_code, &pb)
+            .await
+            .map_err(|err| {
+                error!("error looking up file for {}: {:?}", thread_id, err);
+                DispatchError::NewThreadFailed
+            })?
+        {
+            info!("File already posted on /{}/", board_code);
+            errors.push(PostStatus::DuplicateFile);
+            // only goes if nothing else has the file
+            let file_dir = Path::new(&post_settings.file_dir);
+            remove_files(db, file_dir, pb.attachment.as_ref()).await;
+        }
+    }
+
+    let mut nope = false;
+    if errors.is_empty()
+    {
+        let p = match existing
+        {
+            Some(t) => Some(t),
+            None =>
+            {
+                let delete_hash = delete_hash(
+                    rid,
+                    delete_secret,
+                    DispatchError::NewThreadFailed,
+                )
+                .await?;
+                let thread_num =
+                    match redis_bus.next_post_num(board_code).await
+                    {
+                        Ok(n) => n,
+                        Err(e) =>
+                        {
+                            error!("error allocating post number: {:?}", e);
+                            return Err(DispatchError::NewThreadFailed);
+                        }
+                    };
+
+                let time = pb.time;
+                let mut p = Thread {
+                    t: _thread(),
+                    _id: thread_id.clone(),
+                    _rev: "".to_string(),
+                    board_code: board_code.to_string(),
+                    thread_num,
+                    subject,
+                    body: pb,
+                    bump_time: time,
+                    archived: false,
+                    pinned: false,
+                    priority: 0,
+                    locked: false,
+                    autosage: false,
+                    flagged: filtered.flags,
+                    hidden: false,
+                    ip: Some(*rip),
+                    delete_hash,
+                    comments: None,
+                };
+
+                match db.save(&mut p).await
+                {
+                    Ok(_) =>
+                    {
+                        info!("Thread (main) created");
+                        Some(p)
+                    }
+                    Err(err) =>
+                    {
+                        nope = true;
+                        error!(
+                            "error creating thread {}: {:?}",
+                            thread_id, err
+                        );
+                        errors.push(PostStatus::FailedProcessing);
+                        None
+                    }
+                }
+            }
+        };
+
+        if let Some(mut p) = p
+        {
+            p._id = format!("{}li", thread_id);
+            p._rev = "".to_string();
+            p.ip = None;
+            p.delete_hash = None;
+            match listing_db.save(&mut p).await
+            {
+                Ok(_) =>
+                {
+                    info!("Thread (listing) created");
+                }
+                Err(err) if err.status() == Some(StatusCode::CONFLICT) =>
+                {
+                    info!("Thread (listing) already created");
+                }
+                Err(err) =>
+                {
+                    nope = true;
+                    error!(
+                        "error creating listing thread {}: {:?}",
+                        p._id, err
+                    );
+                    errors.push(PostStatus::FailedProcessing);
+                }
+            }
+        }
+    }
+
+    if nope
+    {
+        return Err(DispatchError::NewThreadFailed);
+    }
+
+    /* the follow-ups go out before the status, since a request that has a
+     * status is never run again and so would never send them.
+     */
+    let prune_msg = serde_json::to_string(&Message::PruneThreads {
+        all_boards: false,
+        board_code: Some(board_code.to_string()),
+    })
+    .map_err(|e| DispatchError::NewThreadCreatedWithError)?;
+
+    let publish_rss = serde_json::to_string(&Message::PublishRss {
+        all_boards: false,
+        board_code: Some(board_code.to_string()),
+    })
+    .map_err(|x| DispatchError::NewThreadCreatedWithError)?;
+
+    match redis_bus
+        .publish("PruneThreads", &prune_msg)
+        .await
+        .and(redis_bus.publish("PublishRss", &publish_rss).await)
+    {
+        Ok(_) => (),
+        Err(e) =>
+        {
+            error!("failed to send refresh messages after creathon: {:?}", e);
+            return Err(DispatchError::NewThreadCreatedWithError);
+        }
+    }
+
+    set_post_status(
+        redis_bus,
+        rid,
+        errors,
+        wait,
+        DispatchError::NewThreadCreatedWithError,
+    )
+    .await
+}
+
+async fn new_comment(
+    db: &Database,
+    listing_db: &Database,
+    post_settings: &SpriteSettings,
+    filters: &Filters,
+    redis_bus: &mut RedisBus,
+    parent_thread_id: &str,
+    mut pb: PostBody,
+    upload: Option<&Upload>,
+    delete_secret: Option<&DeleteSecret>,
+    rid: &Uuid,
+    rip: &IpAddr,
+    board_code: &str,
+    grant: &Grant,
+) -> Result<(), DispatchError>
+{
+    // as with threads, the comment is named after the request
+    let comment_id = rid.simple().to_string();
+    match db.get::<Comment>(&comment_id).await
+    {
+        Ok(c) =>
+        {
+            info!("Comment (main) already created, resuming");
+            let (replies, _) = reply_stats(db, board_code, parent_thread_id)
+                .await
+                .map_err(|err| {
+                    error!(
+                        "error counting comments for {}: {:?}",
+                        parent_thread_id, err
+                    );
+                    DispatchError::NewCommentFailed
+                })?;
+            let bump_limit = post_settings.bump_limit(board_code);
+            let past_limit = replies >= bump_limit;
+            if past_limit
+            {
+                autosage_thread(db, parent_thread_id).await?;
+            }
+            let time = c.body.time;
+            bump_listing(
+                listing_db,
+                parent_thread_id,
+                c,
+                time,
+                replies > bump_limit,
+                past_limit,
+            )
+            .await?;
+            return set_post_status(
+                redis_bus,
+                rid,
+                vec![],
+                None,
+                DispatchError::NewCommentCreatedWithError,
+            )
+            .await;
+        }
+        Err(err) if err.is_not_found() => (),
+        Err(err) =>
+        {
+            error!("error fetching comment {}: {:?}", comment_id, err);
+            return Err(DispatchError::NewCommentFailed);
+        }
+    }
+
+    let filtered = filters
+        .apply(db, board_code, None, &mut pb)
+        .await
+        .map_err(|err| {
+            error!("error loading filters: {:?}", err);
+            DispatchError::NewCommentFailed
+        })?;
+    let mut errors = filtered.rejected;
+
+    if pb.comment.chars().count() as i64
+        > post_settings.post_comment_max_length
+    {
+        info!("Comment exceeded allowed length");
+        errors.push(PostStatus::LargeComment);
+    }
+
+    if is_banned(db, post_settings, redis_bus, board_code, rip)
+        .await
+        .map_err(|_| DispatchError::NewCommentFailed)?
+    {
+        errors.push(PostStatus::BannedIp);
+    }
+
+    if !is_staff(grant, board_code)
+        && board_locked(db, board_code)
+            .await
+            .map_err(|_| DispatchError::NewCommentFailed)?
+    {
+        info!("/{}/ is locked", board_code);
+        errors.push(PostStatus::BoardLocked);
+    }
+
+    let parent = match db.get::<Thread>(parent_thread_id).await
+    {
+        Ok(t)
+            if t.t == _thread() && t.board_code == board_code && !t.hidden =>
+        {
+            Some(t)
+        }
+        Ok(_) => None,
+        Err(err) if err.is_not_found() => None,
+        Err(err) =>
+        {
+            error!("error fetching thread {}: {:?}", parent_thread_id, err);
+            return Err(DispatchError::NewCommentFailed);
+        }
+    };
+
+    match &parent
+    {
+        None => errors.push(PostStatus::NoSuchThread),
+        Some(t) if t.archived => errors.push(PostStatus::ThreadArchived),
+        Some(t) if t.locked && !is_staff(grant, board_code) =>
+        {
+            errors.push(PostStatus::ThreadLocked)
+        }
+        Some(_) => (),
+    }
+
+    let mut wait = None;
+    if errors.is_empty()
+    {
+        let (count, files) =
+            match reply_stats(db, board_code, parent_thread_id).await
+            {
+                Ok(stats) => stats,
+                Err(err) =>
+                {
+                    error!(
+                        "error counting comments for {}: {:?}",
+                        parent_thread_id, err
+                    );
+                    return Err(DispatchError::NewCommentFailed);
+                }
+            };
+
+        if count >= post_settings.max_comments(board_code)
+        {
+            info!("Thread {} is full", parent_thread_id);
+            errors.push(PostStatus::LargeThread);
+        }
+        else if upload.is_some()
+            && files >= post_settings.image_limit(board_code)
+        {
+            info!("Thread {} has reached its image limit", parent_thread_id);
+            errors.push(PostStatus::ImageLimit);
+        }
+        else if let Some(w) = too_fast(
+            redis_bus,
+            post_settings,
+            rid,
+            rip,
+            is_staff(grant, board_code),
+            &reply_cooldown_key(board_code, rip),
+            post_settings.reply_cooldown(board_code),
+        )
+        .await
+        .map_err(|_| DispatchError::NewCommentFailed)?
+        {
+            wait = Some(w);
+            errors.push(PostStatus::TooFast);
+        }
+        else if let Err(status) = attach_upload(
+            post_settings,
+            rid,
+            upload,
+            post_settings.post_comment_max_file_size,
+            board_code,
+            &mut pb,
+        )
+        .await
+        {
+            errors.push(status);
+        }
+        else if is_repost(db, post_settings, board_code, &pb)
+            .await
+            .map_err(|err| {
+                error!("error looking up file for {}: {:?}", comment_id, err);
+                DispatchError::NewCommentFailed
+            })?
+        {
+            info!("File already posted on /{}/", board_code);
+            errors.push(PostStatus::DuplicateFile);
+            // only goes if nothing else has the file
+            let file_dir = Path::new(&post_settings.file_dir);
+            remove_files(db, file_dir, pb.attachment.as_ref()).await;
+        }
+        else
+        {
+            let delete_hash = delete_hash(
+                rid,
+                delete_secret,
+                DispatchError::NewCommentFailed,
+            )
+            .await?;
+            let post_num = match redis_bus.next_post_num(board_code).await
+            {
+                Ok(n) => n,
+                Err(e) =>
+                {
+                    error!("error allocating post number: {:?}", e);
+                    return Err(DispatchError::NewCommentFailed);
+                }
+            };
+
+            let time = pb.time;
+            let mut c = Comment {
+                t: _comment(),
+                _id: comment_id.clone(),
+                _rev: "".to_string(),
+                board_code: board_code.to_string(),
+                post_num,
+                parent_thread_id: parent_thread_id.to_string(),
+                body: pb,
+                archived: false,
+                flagged: filtered.flags,
+                hidden: false,
+                ip: Some(*rip),
+                delete_hash,
+            };
+
+            match db.save(&mut c).await
+            {
+                Ok(_) =>
+                {
+                    info!("Comment (main) created");
+                    // count now includes this comment
+                    let bump_limit = post_settings.bump_limit(board_code);
+                    let past_limit = count + 1 >= bump_limit;
+                    let marked = parent.as_ref().is_some_and(|t| t.autosage);
+                    if past_limit && !marked
+                    {
+                        autosage_thread(db, parent_thread_id).await?;
+                    }
+                    bump_listing(
+                        listing_db,
+                        parent_thread_id,
+                        c,
+                        time,
+                        count + 1 > bump_limit,
+                        past_limit,
+                    )
+                    .await?;
+                }
+                Err(err) =>
+                {
+                    error!("error creating comment {}: {:?}", comment_id, err);
+                    return Err(DispatchError::NewCommentFailed);
+                }
+            }
+        }
+    }
+
+    set_post_status(
+        redis_bus,
+        rid,
+        errors,
+        wait,
+        DispatchError::NewCommentCreatedWithError,
+    )
+    .await
+}
+
+/* number of live comments in a thread, and how many of them have files */
+async fn reply_stats(
+    db: &Database,
+    board_code: &str,
+    thread_id: &str,
+) -> Result<(i64, i64), CouchError>
+{
+    let qp = QueryParams::default()
+        .key(json!([board_code, thread_id]))
+        .reduce(true);
+
+    let result: RawViewCollection<Value, Value> =
+        db.query("user", "comment_stats", Some(qp)).await?;
+
+    Ok(match result.rows.first()
+    {
+        Some(row) => (
+            row.value["count"].as_i64().unwrap_or(0),
+            row.value["sum"].as_i64().unwrap_or(0),
+        ),
+        None => (0, 0),
+    })
+}
+
+/* seconds the poster has left to wait, if they are posting too fast for the
+ * board's cooldown or the cap across all boards. staff are never held up.
+ */
+async fn too_fast(
+    redis_bus: &mut RedisBus,
+    post_settings: &SpriteSettings,
+    rid: &Uuid,
+    rip: &IpAddr,
+    staff: bool,
+    cooldown_key: &str,
+    cooldown_secs: i64,
+) -> Result<Option<i64>, BusError>
+{
+    if staff
+    {
+        return Ok(None);
+    }
+
+    let wait_ms = redis_bus
+        .take_post_slot(
+            cooldown_key,
+            rip,
+            &rid.to_string(),
+            cooldown_secs,
+            post_settings.rate_max_posts,
+            post_settings.rate_window_secs,
+        )
+        .await
+        .map_err(|e| {
+            error!("error checking post rate for {}: {:?}", rip, e);
+            e
+        })?;
+
+    if wait_ms > 0
+    {
+        info!("{} is posting too fast, {}ms to go", rip, wait_ms);
+        // round up, so that waiting as told is always enough
+        Ok(Some((wait_ms + 999) / 1000))
+    }
+    else
+    {
+        Ok(None)
+    }
+}
+
+async fn is_banned(
+    db: &Database,
+    post_settings: &SpriteSettings,
+    redis_bus: &mut RedisBus,
+    board_code: &str,
+    rip: &IpAddr,
+) -> Result<bool, CouchError>
+{
+    match find_ban(
+        db,
+        redis_bus,
+        board_code,
+        rip,
+        post_settings.ban_cache_secs,
+    )
+    .await
+    {
+        Ok(Some(ban)) =>
+        {
+            info!("{} is banned from /{}/ ({})", rip, board_code, ban._id);
+            Ok(true)
+        }
+        Ok(None) => Ok(false),
+        Err(err) =>
+        {
+            error!("error looking up bans for {}: {:?}", rip, err);
+            Err(err)
+        }
+    }
+}
+
+/* whether the post's file is already on a live post on the board, and was
+ * posted within the duplicate window. a window of 0 or less covers however
+ * long the original stays live.
+ */
+async fn is_repost(
+    db: &Database,
+    post_settings: &SpriteSettings,
+    board_code: &str,
+    pb: &PostBody,
+) -> Result<bool, CouchError>
+{
+    let hash = match &pb.attachment
+    {
+        Some(a) => &a.hash,
+        None => return Ok(false),
+    };
+
+    let since = match post_settings.file_duplicate_window
+    {
+        w if w > 0 => (pb.time - chrono::Duration::seconds(w))
+            .timestamp_nanos_opt()
+            .unwrap_or(0),
+        _ => 0,
+    };
+
+    let qp = QueryParams::default()
+        .start_key(json!([board_code, hash, since]))
+        .end_key(json!([board_code, hash, {}]))
+        .limit(1);
+
+    let result: RawViewCollection<Value, Value> =
+        db.query("user", "file_hashes", Some(qp)).await?;
+
+    Ok(!result.rows.is_empty())
+}
+
+/* bring every board's post number counter in Redis up to the highest
+ * number stored in CouchDB, so that a wiped or restored Redis never hands
+ * out a number that is already taken.
+ */
+async fn reseed_post_nums(
+    db: &Database,
+    redis_bus: &mut RedisBus,
+) -> Result<(), String>
+{
+    let qp = QueryParams::default().group(true);
+    let result: RawViewCollection<String, Value> = db
+        .query("user", "post_nums", Some(qp))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    for row in result.rows
+    {
+        let floor = row.value["max"].as_i64().unwrap_or(0);
+        let n = redis_bus
+            .reseed_post_num(&row.key, floor)
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+        info!("Post numbers for /{}/ continue after {}", row.key, n);
+    }
+
+    Ok(())
+}
+
+/* mark a thread as having reached its bump limit */
+async fn autosage_thread(
+    db: &Database,
+    thread_id: &str,
+) -> Result<(), DispatchError>
+{
+    let mut t = match db.get::<Thread>(thread_id).await
+    {
+        Ok(t) => t,
+        Err(err) =>
+        {
+            error!("error fetching thread {}: {:?}", thread_id, err);
+            return Err(DispatchError::NewCommentFailed);
+        }
+    };
+
+    if t.autosage
+    {
+        return Ok(());
+    }
+
+    t.autosage = true;
+    match db.save(&mut t).await
+    {
+        Ok(_) =>
+        {
+            info!("Thread {} reached its bump limit", thread_id);
+            Ok(())
+        }
+        Err(err) =>
+        {
+            error!("error updating thread {}: {:?}", thread_id, err);
+            Err(DispatchError::NewCommentFailed)
+        }
+    }
+}
+
+/* add the comment to the thread's preview in the listing db and, unless the
+ * thread is past its bump limit, move it to the top of its board.
+ */
+async fn bump_listing(
+    listing_db: &Database,
+    thread_id: &str,
+    comment: Comment,
+    time: DateTime<Utc>,
+    sage: bool,
+    autosage: bool,
+) -> Result<(), DispatchError>
+{
+    let listing_id = format!("{}li", thread_id);
+    let mut lt = match listing_db.get::<Thread>(&listing_id).await
+    {
+        Ok(lt) => lt,
+        Err(err) =>
+        {
+            error!("error fetching listing thread {}: {:?}", listing_id, err);
+            return Err(DispatchError::NewCommentFailed);
+        }
+    };
+
+    if !sage
+    {
+        lt.bump_time = time;
+    }
+    lt.autosage |= autosage;
+    let mut preview = lt.comments.take().unwrap_or_default();
+    if !preview.iter().any(|c| c._id == comment._id)
+    {
+        preview.push(Comment {
+            ip: None,
+            delete_hash: None,
+            ..comment
+        });
+    }
+    if preview.len() > LISTING_PREVIEW_COMMENTS
+    {
+        preview.drain(..preview.len() - LISTING_PREVIEW_COMMENTS);
+    }
+    lt.comments = Some(preview);
+
+    match listing_db.save(&mut lt).await
+    {
+        Ok(_) =>
+        {
+            info!("Thread (listing) bumped");
+            Ok(())
+        }
+        Err(err) =>
+        {
+            error!("error bumping listing thread {}: {:?}", listing_id, err);
+            Err(DispatchError::NewCommentFailed)
+        }
+    }
+}
+
+async fn prune_threads(
+    db: &Database,
+    listing_db: &Database,
+    post_settings: &SpriteSettings,
+    all_boards: bool,
+    board_code: Option<&str>,
+) -> Result<(), DispatchError>
+{
+    let boards = match (all_boards, board_code)
+    {
+        (true, _) => match listed_boards(listing_db).await
+        {
+            Ok(boards) => boards,
+            Err(err) =>
+            {
+                error!("error listing boards: {:?}", err);
+                return Err(DispatchError::PruneFailed);
+            }
+        },
+        (false, Some(bc)) => vec![bc.to_string()],
+        (false, None) =>
+        {
+            warn!("Prune requested without a board");
+            return Ok(());
+        }
+    };
+
+    for bc in boards
+    {
+        prune_board(db, listing_db, post_settings.board_max_threads, &bc)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/* every board code that has at least one thread in the listing db */
+async fn listed_boards(
+    listing_db: &Database,
+) -> Result<Vec<String>, CouchError>
+{
+    let qp = QueryParams::default().group(true);
+    let result: RawViewCollection<String, Value> =
+        listing_db.query("user", "boards", Some(qp)).await?;
+
+    Ok(result.rows.into_iter().map(|r| r.key).collect())
+}
+
+/* archive every unpinned thread that has fallen past the board's thread
+ * limit in bump order. pinned threads are neither counted nor pruned.
+ */
+async fn prune_board(
+    db: &Database,
+    listing_db: &Database,
+    max_threads: i64,
+    board_code: &str,
+) -> Result<(), DispatchError>
+{
+    let qp = QueryParams::default()
+        .start_key(json!([board_code, 0, {}]))
+        .end_key(json!([board_code, 0]))
+        .descending(true)
+        .include_docs(true)
+        .skip(max_threads as u64);
+
+    let result: Result<ViewCollection<Value, Value, Thread>, CouchError> =
+        listing_db.query("user", "thread_view", Some(qp)).await;
+
+    let overflow = match result
+    {
+        Ok(vc) => vc.rows.into_iter().filter_map(|r| r.doc),
+        Err(err) =>
+        {
+            error!("error listing threads on {}: {:?}", board_code, err);
+            return Err(DispatchError::PruneFailed);
+        }
+    };
+
+    for lt in overflow
+    {
+        archive_thread(db, listing_db, lt).await?;
+    }
+
+    Ok(())
+}
+
+/* mark a thread and its comments archived in the main db, then drop it
+ * from the listing db. the listing document goes last so that a failure
+ * part way through leaves the thread to be picked up by the next prune.
+ */
+async fn archive_thread(
+    db: &Database,
+    listing_db: &Database,
+    lt: Thread,
+) -> Result<(), DispatchError>
+{
+    let thread_id = lt._id.strip_suffix("li").unwrap_or(&lt._id);
+
+    let qp = QueryParams::default()
+        .start_key(json!([lt.board_code, thread_id, 1]))
+        .end_key(json!([lt.board_code, thread_id, {}]))
+        .include_docs(true);
+
+    let result: Result<ViewCollection<Value, Value, Comment>, CouchError> =
+        db.query("user", "thread_view", Some(qp)).await;
+
+    let mut comments = match result
+    {
+        Ok(vc) => vc
+            .rows
+            .into_iter()
+            .filter_map(|r| r.doc)
+            .map(|mut c| {
+                c.archived = true;
+                c
+            })
+            .collect::<Vec<Comment>>(),
+        Err(err) =>
+        {
+            error!("error fetching comments for {}: {:?}", thread_id, err);
+            return Err(DispatchError::PruneFailed);
+        }
+    };
+
+    if !comments.is_empty()
+    {
+        match db.bulk_docs(&mut comments).await
+        {
+            Ok(results) if results.iter().all(|r| r.is_ok()) => (),
+            Ok(results) =>
+            {
+                error!(
+                    "error archiving comments for {}: {:?}",
+                    thread_id,
+                    results
+                        .into_iter()
+                        .filter_map(|r| r.err())
+                        .collect::<Vec<_>>()
+                );
+                return Err(DispatchError::PruneFailed);
+            }
+            Err(err) =>
+            {
+                error!(
+                    "error archiving comments for {}: {:?}",
+                    thread_id, err
+                );
+                return Err(DispatchError::PruneFailed);
+            }
+        }
+    }
+
+    let archived = match db.get::<Thread>(thread_id).await
+    {
+        Ok(mut t) =>
+        {
+            t.archived = true;
+            db.save(&mut t).await.map(|_| ())
+        }
+        Err(err) => Err(err),
+    };
+
+    if let Err(err) = archived
+    {
+        error!("error archiving thread {}: {:?}", thread_id, err);
+        return Err(DispatchError::PruneFailed);
+    }
+
+    if !listing_db.remove(&lt).await
+    {
+        error!("error removing listing thread {}", lt._id);
+        return Err(DispatchError::PruneFailed);
+    }
+
+    info!("Thread {} archived", thread_id);
+    Ok(())
+}
+
+/* regenerate the feeds of the given board (or of every board), along with
+ * the site-wide feed which any new thread also lands in.
+ */
+async fn publish_feeds(
+    listing_db: &Database,
+    post_settings: &SpriteSettings,
+    all_boards: bool,
+    board_code: Option<&str>,
+) -> Result<(), DispatchError>
+{
+    let boards = match (all_boards, board_code)
+    {
+        (true, _) => match listed_boards(listing_db).await
+        {
+            Ok(boards) => boards,
+            Err(err) =>
+            {
+                error!("error listing boards: {:?}", err);
+                return Err(DispatchError::PublishFeedFailed);
+            }
+        },
+        (false, Some(bc)) => vec![bc.to_string()],
+        (false, None) => vec![],
+    };
+
+    let out_dir = Path::new(&post_settings.feed_output_dir);
+
+    for bc in boards
+    {
+        let threads = newest_threads(
+            listing_db,
+            Some(&bc),
+            post_settings.feed_max_items as u64,
+        )
+        .await?;
+
+        let title = format!("/{}/", bc);
+        let link = format!("{}/board/{}", post_settings.feed_base_url, bc);
+        let info = FeedInfo {
+            title: &title,
+            description: &format!("Newest threads on /{}/", bc),
+            link: &link,
+            base_url: &post_settings.feed_base_url,
+        };
+
+        write_feeds(&out_dir.join("board"), &bc, &info, &threads)?;
+    }
+
+    let threads =
+        newest_threads(listing_db, None, post_settings.feed_max_items as u64)
+            .await?;
+
+    let info = FeedInfo {
+        title: "spriteib",
+        description: "Newest threads on all boards",
+        link: &post_settings.feed_base_url,
+        base_url: &post_settings.feed_base_url,
+    };
+
+    write_feeds(out_dir, "all", &info, &threads)
+}
+
+/* threads in the listing db ordered by creation time, newest first */
+async fn newest_threads(
+    listing_db: &Database,
+    board_code: Option<&str>,
+    limit: u64,
+) -> Result<Vec<Thread>, DispatchError>
+{
+    let qp = QueryParams::default()
+        .descending(true)
+        .include_docs(true)
+        .limit(limit);
+
+    let result: Result<ViewCollection<Value, Value, Thread>, CouchError> =
+        match board_code
+        {
+            Some(bc) =>
+            {
+                let qp = qp.start_key(json!([bc, {}])).end_key(json!([bc]));
+                listing_db.query("user", "newest", Some(qp)).await
+            }
+            None => listing_db.query("user", "newest_all", Some(qp)).await,
+        };
+
+    match result
+    {
+        Ok(vc) => Ok(vc.rows.into_iter().filter_map(|r| r.doc).collect()),
+        Err(err) =>
+        {
+            error!("error fetching newest threads: {:?}", err);
+            Err(DispatchError::PublishFeedFailed)
+        }
+    }
+}
+
+fn write_feeds(
+    dir: &Path,
+    name: &str,
+    info: &FeedInfo,
+    threads: &[Thread],
+) -> Result<(), DispatchError>
+{
+    for kind in [FeedKind::Rss, FeedKind::Atom]
+    {
+        let contents = match kind
+        {
+            FeedKind::Rss => render_rss(info, threads),
+            FeedKind::Atom => render_atom(info, threads),
+        };
+
+        let path = dir.join(format!("{}.{}", name, kind.extension()));
+        if let Err(err) = write_feed(&path, &contents)
+        {
+            error!("error writing feed {}: {:?}", path.display(), err);
+            return Err(DispatchError::PublishFeedFailed);
+        }
+    }
+
+    info!("Feeds for {} published", name);
+    Ok(())
+}
+
+/* `wait` is how many seconds a poster who was too fast has left to wait */
+async fn set_post_status(
+    redis_bus: &mut RedisBus,
+    rid: &Uuid,
+    errors: Vec<PostStatus>,
+    wait: Option<i64>,
+    on_error: DispatchError,
+) -> Result<(), DispatchError>
+{
+    let mut status_message_map: HashMap<&str, Value> = HashMap::new();
+    let mut expiry = 86400_i32;
+    match errors.len()
+    {
+        0 =>
+        {
+            status_message_map.insert("status", json!("ok"));
+        }
+        _ =>
+        {
+            status_message_map.insert("status", json!("error"));
+            let err_str = errors
+                .into_iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+                .to_string();
+
+            status_message_map.insert("errors", json!(err_str));
+            expiry = 604800;
+        }
+    };
+
+    if let Some(w) = wait
+    {
+        status_message_map.insert("wait", json!(w));
+    }
+
+    let status_json = serde_json::to_string(&status_message_map);
+    match status_json
+    {
+        Ok(val) =>
+        {
+            match redis_bus.set_status(rid.to_string(), val, expiry).await
+            {
+                Ok(_) => Ok(()),
+                Err(e) =>
+                {
+                    error!("error setting post status: {:?}", e);
+                    Err(on_error)
+                }
+            }
+        }
+        Err(e) =>
+        {
+            error!("error serializing post status: {:?}", e);
+            Err(on_error)
+        }
+    }
+}
+
+/* dispatch one message off the bus and acknowledge it. transient failures
+ * are retried with exponential backoff; a message that still fails, or
+ * could never succeed, is moved to the dead letter stream. if even that
+ * fails the message is left pending, to be claimed again once it has sat
+ * idle for long enough. while it is being dispatched its idle time is
+ * reset every half `claim_idle`, since waiting out another worker's claim
+ * on a request can take longer than that.
+ */
+#[allow(clippy::too_many_arguments)]
+async fn handle_message(
+    msg: BusMessage,
+    db: &Database,
+    listing_db: &Database,
+    sprite_settings: &SpriteSettings,
+    retry_settings: &RetrySettings,
+    filters: &Filters,
+    bus: &mut RedisBus,
+    group: &str,
+    consumer: &str,
+    claim_idle: Duration,
+) -> Result<(), String>
+{
+    let outcome = {
+        let mut keeper = bus.clone();
+        let dispatch = async {
+            match serde_json::from_str::<Message>(&msg.payload)
+            {
+                Ok(m) =>
+                {
+                    dispatch_with_retry(
+                        &m,
+                        db,
+                        listing_db,
+                        sprite_settings,
+                        retry_settings,
+                        filters,
+                        bus,
+                    )
+                    .await
+                }
+                Err(e) =>
+                {
+                    warn!("Could not deserialize '{}': {}", &msg.payload, e);
+                    Err((e.to_string(), 0))
+                }
+            }
+        };
+        tokio::pin!(dispatch);
+
+        let mut keep_claim = tokio::time::interval(claim_idle / 2);
+        keep_claim.tick().await;
+        loop
+        {
+            tokio::select! {
+                outcome = &mut dispatch => break outcome,
+                _ = keep_claim.tick() =>
+                {
+                    if let Err(e) = keeper
+                        .keep_claim(&msg.channel, group, consumer, &msg.id)
+                        .await
+                    {
+                        warn!("Could not keep claim on {}: {:?}", msg.id, e);
+                    }
+                }
+            }
+        }
+    };
+
+    if let Err((error, attempts)) = outcome
+    {
+        let letter = DeadLetter {
+            channel: msg.channel.clone(),
+            payload: msg.payload.clone(),
+            error,
+            attempts,
+            time: Utc::now(),
+        };
+
+        if let Err(e) = bus.dead_letter(&letter).await
+        {
+            error!("Could not dead-letter {}: {:?}", msg.id, e);
+            return Err(format!("{:?}", e));
+        }
+        warn!(
+            "Message {} on {} dead-lettered after {} attempts",
+            msg.id, msg.channel, attempts
+        );
+    }
+
+    bus.ack(&msg.channel, group, &msg.id).await.map_err(|e| {
+        error!("Could not acknowledge {}: {:?}", msg.id, e);
+        format!("{:?}", e)
+    })
+}
+
+async fn dispatch_with_retry(
+    message: &Message,
+    db: &Database,
+    listing_db: &Database,
+    sprite_settings: &SpriteSettings,
+    retry_settings: &RetrySettings,
+    filters: &Filters,
+    bus: &mut RedisBus,
+) -> Result<(), (String, u32)>
+{
+    let mut attempt = 1_u32;
+    loop
+    {
+        match dispatch_message(
+            message,
+            db,
+            listing_db,
+            sprite_settings,
+            filters,
+            bus,
+        )
+        .await
+        {
+            Ok(()) => return Ok(()),
+            /* another worker holds the request. it either finishes, and the
+             * request is found processed next time, or its claim runs out
+             * and this one takes over; neither is a failed attempt.
+             */
+            Err(DispatchError::RequestInProgress) =>
+            {
+                let delay = retry_settings.delay(attempt);
+                debug!("Request in progress elsewhere, waiting {:?}", delay);
+                tokio::time::sleep(delay).await;
+            }
+            Err(e)
+                if e.is_transient()
+                    && (attempt as i64) < retry_settings.max_attempts =>
+            {
+                let delay = retry_settings.delay(attempt);
+                warn!(
+                    "Dispatch failed, {:?} (attempt {}), retrying in {:?}",
+                    e, attempt, delay
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) =>
+            {
+                error!("Dispatch failed, {:?} (attempt {})", e, attempt);
+                return Err((e.to_string(), attempt));
+            }
+        }
+    }
+}
+
+/* `spriteib_wrk dead-letters list [count]` prints dead letters, oldest
+ * first. `spriteib_wrk dead-letters requeue <id>...` puts them back on
+ * their channels, and `requeue all` does so for every one of them.
+ */
+async fn dead_letters_command(bus: &mut RedisBus, args: &[String])
+{
+    match args.first().map(String::as_str)
+    {
+        Some("list") =>
+        {
+            let count =
+                args.get(1).and_then(|c| c.parse().ok()).unwrap_or(100);
+            match bus.dead_letters(count).await
+            {
+                Ok(letters) =>
+                {
+                    for (id, l) in letters
+                    {
+                        println!(
+                            "{} {} attempts={} at={}\n  error: {}\n  \
+                             payload: {}",
+                            id,
+                            l.channel,
+                            l.attempts,
+                            l.time.to_rfc3339(),
+                            l.error,
+                            l.payload
+                        );
+                    }
+                }
+                Err(e) => eprintln!("Could not read dead letters: {:?}", e),
+            }
+        }
+        Some("requeue") =>
+        {
+            let ids = match args.get(1).map(String::as_str)
+            {
+                Some("all") => match bus.dead_letters(i64::MAX as usize).await
+                {
+                    Ok(letters) =>
+                    {
+                        letters.into_iter().map(|(id, _)| id).collect()
+                    }
+                    Err(e) =>
+                    {
+                        eprintln!("Could not read dead letters: {:?}", e);
+                        return;
+                    }
+                },
+                _ => args[1..].to_vec(),
+            };
+
+            for id in ids
+            {
+                match bus.requeue_dead_letter(&id).await
+                {
+                    Ok(true) => println!("{} requeued", id),
+                    Ok(false) => eprintln!("{} not found", id),
+                    Err(e) => eprintln!("Could not requeue {}: {:?}", id, e),
+                }
+            }
+        }
+        _ => eprintln!(
+            "usage: spriteib_wrk dead-letters list [count]\n       \
+             spriteib_wrk dead-letters requeue <id>... | all"
+        ),
+    }
+}
+
+/* `spriteib_wrk bans list` prints every ban. `spriteib_wrk bans add <range>
+ * <board|all> <hours|0> <staff> <reason>...` bans an address or CIDR range,
+ * for good if hours is 0, and `spriteib_wrk bans lift <id>...` removes
+ * bans. either can take up to ban.cache-secs to be noticed.
+ */
+async fn bans_command(db: &Database, args: &[String])
+{
+    match args.first().map(String::as_str)
+    {
+        Some("list") =>
+        {
+            let qp = QueryParams::default().include_docs(true);
+            let result: Result<
+                ViewCollection<String, Value, Ban>,
+                CouchError,
+            > = db.query("user", "bans", Some(qp)).await;
+
+            match result
+            {
+                Ok(vc) =>
+                {
+                    for b in vc.rows.into_iter().filter_map(|r| r.doc)
+                    {
+                        println!(
+                            "{} {} on {} by {} at={} expires={}\n  reason: {}",
+                            b._id,
+                            b.range,
+                            b.board_code.as_deref().unwrap_or("all"),
+                            b.staff,
+                            b.time.to_rfc3339(),
+                            b.expires
+                                .map(|e| e.to_rfc3339())
+                                .unwrap_or("never".to_string()),
+                            b.reason
+                        );
+                    }
+                }
+                Err(e) => eprintln!("Could not read bans: {:?}", e),
+            }
+        }
+        Some("add") if args.len() >= 6 =>
+        {
+            if parse_range(&args[1]).is_none()
+            {
+                eprintln!("{} is not an address or CIDR range", args[1]);
+                return;
+            }
+
+            let hours = match args[3].parse::<i64>()
+            {
+                Ok(h) if h >= 0 => h,
+                _ =>
+                {
+                    eprintln!("{} is not a number of hours", args[3]);
+                    return;
+                }
+            };
+
+            let time = Utc::now();
+            let mut ban = Ban {
+                t: _ban(),
+                _id: "".to_string(),
+                _rev: "".to_string(),
+                range: args[1].clone(),
+                board_code: match args[2].as_str()
+                {
+                    "all" => None,
+                    bc => Some(bc.to_string()),
+                },
+                reason: args[5..].join(" "),
+                staff: args[4].clone(),
+                time,
+                expires: match hours
+                {
+                    0 => None,
+                    h => Some(time + chrono::Duration::hours(h)),
+                },
+            };
+
+            match db.save(&mut ban).await
+            {
+                Ok(_) => println!("{} banned as {}", ban.range, ban._id),
+                Err(e) => eprintln!("Could not save ban: {:?}", e),
+            }
+        }
+        Some("lift") =>
+        {
+            for id in &args[1..]
+            {
+                match db.get::<Ban>(id).await
+                {
+                    Ok(b) if b.t == _ban() =>
+                    {
+                        if db.remove(&b).await
+                        {
+                            println!("{} lifted", id);
+                        }
+                        else
+                        {
+                            eprintln!("Could not lift {}", id);
+                        }
+                    }
+                    Ok(_) => eprintln!("{} not found", id),
+                    Err(e) if e.is_not_found() =>
+                    {
+                        eprintln!("{} not found", id)
+                    }
+                    Err(e) => eprintln!("Could not read {}: {:?}", id, e),
+                }
+            }
+        }
+        _ => eprintln!(
+            "usage: spriteib_wrk bans list\n       spriteib_wrk bans add \
+             <range> <board|all> <hours|0> <staff> <reason>...\n       \
+             spriteib_wrk bans lift <id>..."
+        ),
+    }
+}
+
+/* `spriteib_wrk staff list` prints every staff account. `spriteib_wrk
+ * staff add <username> <admin|mod|janny> <board,...|all>` adds one and
+ * `staff password <username>` changes its password, both reading the
+ * password from stdin. `staff totp <username> [off]` sets up or turns off
+ * the second factor, and `staff remove <username>...` removes accounts.
+ */
+async fn staff_command(db: &Database, args: &[String])
+{
+    match (args.first().map(String::as_str), args.get(1))
+    {
+        (Some("list"), _) =>
+        {
+            let qp = QueryParams::default()
+                .start_key(staff_doc_id(""))
+                .end_key(staff_doc_id("\u{fff0}"));
+
+            match db.get_all_params::<StaffAccount>(Some(qp)).await
+            {
+                Ok(dc) =>
+                {
+                    for a in dc.rows.into_iter().filter(|a| a.t == _staff())
+                    {
+                        println!(
+                            "{} {:?} on {}{}",
+                            a.username,
+                            a.role,
+                            a.boards
+                                .map(|b| b.join(","))
+                                .unwrap_or("all".to_string()),
+                            a.totp_secret.map_or("", |_| " (totp)")
+                        );
+                    }
+                }
+                Err(e) => eprintln!("Could not read staff accounts: {:?}", e),
+            }
+        }
+        (Some("add"), Some(username)) if args.len() == 4 =>
+        {
+            let role = match args[2].as_str()
+            {
+                "admin" => Role::Admin,
+                "mod" => Role::Mod,
+                "janny" => Role::Janny,
+                r =>
+                {
+                    eprintln!("{} is not a staff role", r);
+                    return;
+                }
+            };
+
+            let mut account = StaffAccount {
+                t: _staff(),
+                _id: staff_doc_id(username),
+                _rev: "".to_string(),
+                username: username.clone(),
+                password: "".to_string(),
+                role,
+                boards: match args[3].as_str()
+                {
+                    "all" => None,
+                    b => Some(b.split(',').map(str::to_string).collect()),
+                },
+                totp_secret: None,
+                totp_last_step: None,
+            };
+
+            if set_password(&mut account)
+            {
+                match db.create(&mut account).await
+                {
+                    Ok(_) => println!("{} added", username),
+                    Err(e) => eprintln!("Could not add {}: {:?}", username, e),
+                }
+            }
+        }
+        (Some("password"), Some(username)) =>
+        {
+            if let Some(mut account) = staff_account(db, username).await
+            {
+                if set_password(&mut account)
+                {
+                    match db.save(&mut account).await
+                    {
+                        Ok(_) => println!("Password for {} changed", username),
+                        Err(e) =>
+                        {
+                            eprintln!("Could not save {}: {:?}", username, e)
+                        }
+                    }
+                }
+            }
+        }
+        (Some("totp"), Some(username)) =>
+        {
+            if let Some(mut account) = staff_account(db, username).await
+            {
+                let url = match args.get(2).map(String::as_str)
+                {
+                    Some("off") =>
+                    {
+                        account.totp_secret = None;
+                        None
+                    }
+                    _ => Some(account.enable_totp()),
+                };
+
+                match (db.save(&mut account).await, url)
+                {
+                    (Ok(_), Some(url)) =>
+                    {
+                        println!("Add this to {}'s authenticator:", username);
+                        println!("{}", url);
+                    }
+                    (Ok(_), None) => println!("TOTP off for {}", username),
+                    (Err(e), _) =>
+                    {
+                        eprintln!("Could not save {}: {:?}", username, e)
+                    }
+                }
+            }
+        }
+        (Some("remove"), Some(_)) =>
+        {
+            for username in &args[1..]
+            {
+                if let Some(account) = staff_account(db, username).await
+                {
+                    if db.remove(&account).await
+                    {
+                        println!("{} removed", username);
+                    }
+                    else
+                    {
+                        eprintln!("Could not remove {}", username);
+                    }
+                }
+            }
+        }
+        _ => eprintln!(
+            "usage: spriteib_wrk staff list\n       spriteib_wrk staff add \
+             <username> <admin|mod|janny> <board,...|all>\n       \
+             spriteib_wrk staff password <username>\n       spriteib_wrk \
+             staff totp <username> [off]\n       spriteib_wrk staff remove \
+             <username>..."
+        ),
+    }
+}
+
+async fn staff_account(db: &Database, username: &str) -> Option<StaffAccount>
+{
+    match db.get::<StaffAccount>(&staff_doc_id(username)).await
+    {
+        Ok(a) if a.t == _staff() => Some(a),
+        Ok(_) =>
+        {
+            eprintln!("{} not found", username);
+            None
+        }
+        Err(e) if e.is_not_found() =>
+        {
+            eprintln!("{} not found", username);
+            None
+        }
+        Err(e) =>
+        {
+            eprintln!("Could not read {}: {:?}", username, e);
+            None
+        }
+    }
+}
+
+/* read a password from stdin and hash it into the account */
+fn set_password(account: &mut StaffAccount) -> bool
+{
+    eprint!("Password for {}: ", account.username);
+    let mut password = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut password)
+    {
+        eprintln!("Could not read password: {:?}", e);
+        return false;
+    }
+
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty()
+    {
+        eprintln!("Password can't be empty");
+        return false;
+    }
+
+    match account.set_password(password)
+    {
+        Ok(()) => true,
+        Err(e) =>
+        {
+            eprintln!("Could not hash password: {:?}", e);
+            false
+        }
+    }
+}
+
+/* `spriteib_wrk filters list` prints every filter, and `spriteib_wrk
+ * filters remove <id>...` removes filters. `spriteib_wrk filters add
+ * <board|all> <comment|name|email|subject> <literal|regex>
+ * <reject|flag|replace> <pattern> [replacement]` adds one. workers pick up
+ * changes within filter.reload-secs. `spriteib_wrk filters flagged` lists
+ * the posts filters have flagged.
+ */
+async fn filters_command(db: &Database, args: &[String])
+{
+    match args.first().map(String::as_str)
+    {
+        Some("list") =>
+        {
+            let qp = QueryParams::default().include_docs(true);
+            let result: Result<
+                ViewCollection<String, Value, Filter>,
+                CouchError,
+            > = db.query("user", "filters", Some(qp)).await;
+
+            match result
+            {
+                Ok(vc) =>
+                {
+                    for f in vc.rows.into_iter().filter_map(|r| r.doc)
+                    {
+                        println!(
+                            "{} on {} {:?} {} {:?}: {}{}",
+                            f._id,
+                            f.board_code.as_deref().unwrap_or("all"),
+                            f.field,
+                            if f.regex { "regex" } else { "literal" },
+                            f.action,
+                            f.pattern,
+                            match f.action
+                            {
+                                FilterAction::Replace =>
+                                {
+                                    format!(" -> {}", f.replacement)
+                                }
+                                _ => "".to_string(),
+                            }
+                        );
+                    }
+                }
+                Err(e) => eprintln!("Could not read filters: {:?}", e),
+            }
+        }
+        Some("add") if args.len() >= 6 =>
+        {
+            let field = serde_json::from_value::<FilterField>(json!(args[2]));
+            let action =
+                serde_json::from_value::<FilterAction>(json!(args[4]));
+            let regex = match args[3].as_str()
+            {
+                "literal" => Some(false),
+                "regex" => Some(true),
+                _ => None,
+            };
+
+            let (field, regex, action) = match (field, regex, action)
+            {
+                (Ok(f), Some(r), Ok(a)) => (f, r, a),
+                _ =>
+                {
+                    eprintln!("Unknown field, pattern kind or action");
+                    return;
+                }
+            };
+
+            if regex
+            {
+                if let Err(e) = regex::Regex::new(&args[5])
+                {
+                    eprintln!("Bad pattern: {}", e);
+                    return;
+                }
+            }
+
+            let mut filter = Filter {
+                t: _filter(),
+                _id: "".to_string(),
+                _rev: "".to_string(),
+                board_code: match args[1].as_str()
+                {
+                    "all" => None,
+                    bc => Some(bc.to_string()),
+                },
+                field,
+                pattern: args[5].clone(),
+                regex,
+                action,
+                replacement: args.get(6).cloned().unwrap_or_default(),
+            };
+
+            match db.save(&mut filter).await
+            {
+                Ok(_) => println!("Filter added as {}", filter._id),
+                Err(e) => eprintln!("Could not save filter: {:?}", e),
+            }
+        }
+        Some("remove") =>
+        {
+            for id in &args[1..]
+            {
+                match db.get::<Filter>(id).await
+                {
+                    Ok(f) if f.t == _filter() =>
+                    {
+                        if db.remove(&f).await
+                        {
+                            println!("{} removed", id);
+                        }
+                        else
+                        {
+                            eprintln!("Could not remove {}", id);
+                        }
+                    }
+                    Ok(_) => eprintln!("{} not found", id),
+                    Err(e) if e.is_not_found() =>
+                    {
+                        eprintln!("{} not found", id)
+                    }
+                    Err(e) => eprintln!("Could not read {}: {:?}", id, e),
+                }
+            }
+        }
+        Some("flagged") =>
+        {
+            let result: Result<RawViewCollection<Value, Value>, CouchError> =
+                db.query("user", "flagged", None).await;
+
+            match result
+            {
+                Ok(vc) =>
+                {
+                    for row in vc.rows
+                    {
+                        println!(
+                            "{} on {} by {}",
+                            row.id.unwrap_or_default(),
+                            row.key[0],
+                            row.value
+                        );
+                    }
+                }
+                Err(e) => eprintln!("Could not read flagged posts: {:?}", e),
+            }
+        }
+        _ => eprintln!(
+            "usage: spriteib_wrk filters list\n       spriteib_wrk filters \
+             add <board|all> <comment|name|email|subject> <literal|regex> \
+             <reject|flag|replace> <pattern> [replacement]\n       \
+             spriteib_wrk filters remove <id>...\n       spriteib_wrk \
+             filters flagged"
+        ),
+    }
+}
+
+/* `spriteib_wrk lock thread <board> <id> [off]` and `spriteib_wrk lock
+ * board <board> [off]` lock or unlock a thread or a whole board, by sending
+ * the workers the same message staff would.
+ */
+async fn lock_command(bus: &mut RedisBus, args: &[String])
+{
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    let (channel, message) = match args.as_slice()
+    {
+        // "off" is no thread id, but would be taken for one
+        ["thread", bc, id, rest @ ..]
+            if *id != "off" && matches!(rest, [] | ["off"]) =>
+        {
+            (
+                "LockThread",
+                Message::LockThread {
+                    thread_id: id.to_string(),
+                    board_code: bc.to_string(),
+                    locked: rest.is_empty(),
+                    grant: Grant::global(Role::Admin),
+                },
+            )
+        }
+        ["board", bc, rest @ ..] if matches!(rest, [] | ["off"]) => (
+            "LockBoard",
+            Message::LockBoard {
+                board_code: bc.to_string(),
+                locked: rest.is_empty(),
+                grant: Grant::global(Role::Admin),
+            },
+        ),
+        _ =>
+        {
+            eprintln!(
+                "usage: spriteib_wrk lock thread <board> <id> [off]\n       \
+                 spriteib_wrk lock board <board> [off]"
+            );
+            return;
+        }
+    };
+
+    send_command(bus, channel, &message).await;
+}
+
+/* `spriteib_wrk pin <board> <id> [priority|off]` pins a thread, or unpins
+ * it with "off". pinned threads with higher priorities are listed first.
+ */
+async fn pin_command(bus: &mut RedisBus, args: &[String])
+{
+    let (bc, id, rest) = match args
+    {
+        [bc, id, rest @ ..] => (bc, id, rest.first().map(String::as_str)),
+        _ =>
+        {
+            eprintln!("usage: spriteib_wrk pin <board> <id> [priority|off]");
+            return;
+        }
+    };
+
+    let (pinned, priority) = match rest
+    {
+        None => (true, 0),
+        Some("off") => (false, 0),
+        Some(p) => match p.parse::<i32>()
+        {
+            Ok(p) => (true, p),
+            Err(_) =>
+            {
+                eprintln!("Priority must be a number");
+                return;
+            }
+        },
+    };
+
+    let message = Message::SetPinned {
+        thread_id: id.clone(),
+        board_code: bc.clone(),
+        pinned,
+        priority,
+        grant: Grant::global(Role::Admin),
+    };
+
+    send_command(bus, "SetPinned", &message).await;
+}
+
+/* `spriteib_wrk delete <post|thread> <board> <id> <hide|remove> [<hours|0>
+ * <board|all> <staff> <reason>...]` deletes a post, or a thread with all
+ * its comments, banning whoever posted it if a ban is given. a post can be
+ * a thread too; "thread" makes sure it is one. the ban is recorded as the
+ * staff member's.
+ */
+async fn delete_command(bus: &mut RedisBus, args: &[String])
+{
+    let usage = "usage: spriteib_wrk delete <post|thread> <board> <id> \
+                 <hide|remove> [<hours|0> <board|all> <staff> <reason>...]";
+
+    let (kind, bc, id, mode, ban) = match args
+    {
+        [kind, bc, id, mode, ban @ ..]
+            if (kind == "post" || kind == "thread")
+                && (ban.is_empty() || ban.len() >= 4) =>
+        {
+            (kind, bc, id, mode, ban)
+        }
+        _ =>
+        {
+            eprintln!("{}", usage);
+            return;
+        }
+    };
+
+    let mode = match mode.as_str()
+    {
+        "hide" => DeleteMode::Hide,
+        "remove" => DeleteMode::Remove,
+        _ =>
+        {
+            eprintln!("{}", usage);
+            return;
+        }
+    };
+
+    let (ban, staff) = match ban
+    {
+        [] => (None, None),
+        [hours, scope, staff, reason @ ..] => match hours.parse::<i64>()
+        {
+            Ok(h) if h >= 0 => (
+                Some(BanPoster {
+                    reason: reason.join(" "),
+                    hours: h,
+                    all_boards: scope == "all",
+                }),
+                Some(staff.clone()),
+            ),
+            _ =>
+            {
+                eprintln!("{} is not a number of hours", hours);
+                return;
+            }
+        },
+        _ =>
+        {
+            eprintln!("{}", usage);
+            return;
+        }
+    };
+
+    let grant = Grant {
+        staff,
+        ..Grant::global(Role::Admin)
+    };
+    let (channel, message) = match kind.as_str()
+    {
+        "thread" => (
+            "DeleteThread",
+            Message::DeleteThread {
+                thread_id: id.clone(),
+                board_code: bc.clone(),
+                mode,
+                ban,
+                grant,
+            },
+        ),
+        _ => (
+            "DeletePost",
+            Message::DeletePost {
+                post_id: id.clone(),
+                board_code: bc.clone(),
+                mode,
+                ban,
+                grant,
+            },
+        ),
+    };
+
+    send_command(bus, channel, &message).await;
+}
+
+async fn send_command(bus: &mut RedisBus, channel: &str, message: &Message)
+{
+    let payload = match serde_json::to_string(message)
+    {
+        Ok(p) => p,
+        Err(e) =>
+        {
+            eprintln!("Could not serialize {}: {:?}", channel, e);
+            return;
+        }
+    };
+
+    match bus.publish(channel, &payload).await
+    {
+        Ok(()) => println!("{} sent", channel),
+        Err(e) => eprintln!("Could not send {}: {:?}", channel, e),
+    }
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 5)]
+async fn main() -> Result<(), std::io::Error>
+{
+    env_logger::init();
+    let s = Config::builder()
+        .add_source(config::File::with_name("settings"))
+        .build()
+        .unwrap();
+
+    const channels: &[&str] = &[
+        "NewThread",
+        "NewComment",
+        "PruneThreads",
+        "PublishRss",
+        "LockThread",
+        "LockBoard",
+        "SetPinned",
+        "DeletePost",
+        "DeleteThread",
+        "UserDeletePost",
+    ];
+
+    let sprite_settings = get_sprite_settings(&s).unwrap();
+    let couch_settings = get_couch_settings(&s).unwrap();
+    let redis_settings = get_redis_settings(&s).unwrap();
+    let retry_settings = get_retry_settings(&s).unwrap();
+
+    let client = couch_rs::Client::new(
+        &couch_settings.host,
+        &couch_settings.username,
+        &couch_settings.password,
+    )
+    .expect("Could not create couchdb client");
+
+    let db = client
+        .db(&couch_settings.db_spriteib)
         .await
         .expect("Could not access spriteib db");
 
     let listing_db = client
-        .db(&couch_settings.db_spriteib)
+        .db(&couch_settings.db_listing)
         .await
         .expect("Could not access spriteib listing db");
 
     let mut bus = RedisBus {
         uri: redis_settings.connection_string,
         connection: None,
+        stream_max_length: redis_settings.stream_max_length as usize,
     };
 
     match bus.connect().await
@@ -294,102 +2958,383 @@ async fn main() -> Result<(), std::io::Error>
         Err(e) => panic!("{}", e),
     }
 
-    if !db.exists("_design/user").await
+    let args = std::env::args().collect::<Vec<String>>();
+    if args.get(1).map(String::as_str) == Some("dead-letters")
     {
-        let thread_view = CouchFunc {
-            map: "function (doc) {
-                if (doc.t == \"thread\" && !doc.archived) {
-                    emit([doc.bc, doc._id, 0], doc.body)
-                }
-                else if (doc.t == \"comment\") {
-                    emit([doc.bc, doc.parent_thread_id, doc.pid], doc.body)
+        dead_letters_command(&mut bus, &args[2..]).await;
+        return Ok(());
+    }
+
+    if args.get(1).map(String::as_str) == Some("lock")
+    {
+        lock_command(&mut bus, &args[2..]).await;
+        return Ok(());
+    }
+
+    if args.get(1).map(String::as_str) == Some("delete")
+    {
+        delete_command(&mut bus, &args[2..]).await;
+        return Ok(());
+    }
+
+    if args.get(1).map(String::as_str) == Some("pin")
+    {
+        pin_command(&mut bus, &args[2..]).await;
+        return Ok(());
+    }
+
+    let thread_view = CouchFunc {
+        map: "function (doc) {
+            if (doc.t == \"thread\" && !doc.archived && !doc.hidden) {
+                var op = {subject: doc.subject,
+                          autosage: doc.autosage};
+                for (var k in doc.body) {
+                    op[k] = doc.body[k];
                 }
-            }"
-            .to_string(),
-            reduce: None,
-        };
+                emit([doc.bc, doc._id, 0], op)
+            }
+            else if (doc.t == \"comment\" && !doc.archived
+                     && !doc.hidden) {
+                emit([doc.bc, doc.parent_thread_id, doc.pid], doc.body)
+            }
+        }"
+        .to_string(),
+        reduce: None,
+    };
 
-        let views = CouchViews::new("thread_view", thread_view);
-        db.create_view("user", views)
-            .await
-            .expect("Could not create view");
+    let comment_stats = CouchFunc {
+        map: "function (doc) {
+            if (doc.t == \"comment\" && !doc.archived && !doc.hidden) {
+                emit([doc.bc, doc.parent_thread_id],
+                     doc.body.attachment ? 1 : 0)
+            }
+        }"
+        .to_string(),
+        reduce: Some("_stats".to_string()),
+    };
+
+    let post_nums = CouchFunc {
+        map: "function (doc) {
+            if (doc.t == \"thread\") {
+                emit(doc.bc, doc.tid)
+            }
+            else if (doc.t == \"comment\") {
+                emit(doc.bc, doc.pid)
+            }
+        }"
+        .to_string(),
+        reduce: Some("_stats".to_string()),
+    };
+
+    let file_hashes = CouchFunc {
+        map: "function (doc) {
+            var a = doc.body && doc.body.attachment;
+            if ((doc.t == \"thread\" || doc.t == \"comment\")
+                && !doc.archived && !doc.hidden && a) {
+                emit([doc.bc, a.hash, doc.body.time], null)
+            }
+        }"
+        .to_string(),
+        reduce: None,
+    };
+
+    // every comment a thread ever had, archived and hidden ones too
+    let thread_comments = CouchFunc {
+        map: "function (doc) {
+            if (doc.t == \"comment\") {
+                emit(doc.parent_thread_id, null)
+            }
+        }"
+        .to_string(),
+        reduce: None,
+    };
+
+    // every post with each file, for telling when a file can go
+    let file_refs = CouchFunc {
+        map: "function (doc) {
+            var a = doc.body && doc.body.attachment;
+            if ((doc.t == \"thread\" || doc.t == \"comment\") && a) {
+                emit(a.hash, null)
+            }
+        }"
+        .to_string(),
+        reduce: None,
+    };
+
+    // global bans are filed under "*"
+    let bans = CouchFunc {
+        map: "function (doc) {
+            if (doc.t == \"ban\") {
+                emit(doc.bc || \"*\", null)
+            }
+        }"
+        .to_string(),
+        reduce: None,
+    };
+
+    let filters = CouchFunc {
+        map: "function (doc) {
+            if (doc.t == \"filter\") {
+                emit(doc.bc || \"*\", null)
+            }
+        }"
+        .to_string(),
+        reduce: None,
+    };
+
+    // posts that filters have marked for review
+    let flagged = CouchFunc {
+        map: "function (doc) {
+            if ((doc.t == \"thread\" || doc.t == \"comment\")
+                && doc.flagged && doc.flagged.length > 0
+                && !doc.hidden) {
+                emit([doc.bc, doc.body.time], doc.flagged)
+            }
+        }"
+        .to_string(),
+        reduce: None,
+    };
+
+    let mut views = CouchViews::new("thread_view", thread_view);
+    views.add("comment_stats", comment_stats);
+    views.add("post_nums", post_nums);
+    views.add("file_hashes", file_hashes);
+    views.add("thread_comments", thread_comments);
+    views.add("file_refs", file_refs);
+    views.add("bans", bans);
+    views.add("filters", filters);
+    views.add("flagged", flagged);
+    install_views(&db, views, VIEWS_VERSION).await;
+
+    /* pinned threads sort after unpinned ones, so querying in
+     * descending order puts them first, highest priority first,
+     * followed by the most recently bumped threads.
+     */
+    let board_view = CouchFunc {
+        map: "function (doc) {
+            if (doc.t == \"thread\" && !doc.archived) {
+                var p = doc.pinned ? 1 : 0;
+                emit([doc.bc, p, p ? doc.priority || 0 : 0,
+                      doc.bump_time], null)
+            }
+        }"
+        .to_string(),
+        reduce: None,
+    };
+
+    let boards = CouchFunc {
+        map: "function (doc) {
+            if (doc.t == \"thread\") {
+                emit(doc.bc, null)
+            }
+        }"
+        .to_string(),
+        reduce: Some("_count".to_string()),
+    };
+
+    let newest = CouchFunc {
+        map: "function (doc) {
+            if (doc.t == \"thread\") {
+                emit([doc.bc, doc.body.time], null)
+            }
+        }"
+        .to_string(),
+        reduce: None,
+    };
+
+    let newest_all = CouchFunc {
+        map: "function (doc) {
+            if (doc.t == \"thread\") {
+                emit(doc.body.time, null)
+            }
+        }"
+        .to_string(),
+        reduce: None,
+    };
+
+    let mut views = CouchViews::new("thread_view", board_view);
+    views.add("boards", boards);
+    views.add("newest", newest);
+    views.add("newest_all", newest_all);
+    install_views(&listing_db, views, LISTING_VIEWS_VERSION).await;
+
+    if args.get(1).map(String::as_str) == Some("bans")
+    {
+        bans_command(&db, &args[2..]).await;
+        return Ok(());
     }
 
-    if !listing_db.exists("_design/user").await
+    if args.get(1).map(String::as_str) == Some("staff")
     {
-        let board_view = CouchFunc {
-            map: "function (doc) { emit([doc.bc, doc._id, 0], null) }"
-                .to_string(),
-            reduce: None,
-        };
+        staff_command(&db, &args[2..]).await;
+        return Ok(());
+    }
 
-        let views = CouchViews::new("thread_view", board_view);
-        listing_db
-            .create_view("user", views)
-            .await
-            .expect("Could not create view");
+    if args.get(1).map(String::as_str) == Some("filters")
+    {
+        filters_command(&db, &args[2..]).await;
+        return Ok(());
     }
 
-    let mut ps = match bus.pubsub().await
+    match reseed_post_nums(&db, &mut bus).await
     {
-        Ok(p) =>
-        {
-            info!("Redis pub/sub client obtained");
-            p
-        }
-        Err(e) => panic!("{}", e),
-    };
+        Ok(()) => info!("Post number counters reseeded"),
+        Err(e) => panic!("Could not reseed post numbers: {}", e),
+    }
+
+    let group = redis_settings.stream_group;
+    let consumer = format!("worker-{}", Uuid::new_v4());
+    let claim_idle =
+        Duration::from_millis(redis_settings.stream_claim_idle_ms as u64);
 
     for c in channels
     {
-        match ps.subscribe(c).await
+        match bus.create_group(c, &group).await
         {
-            Ok(()) => info!("Created Redis subscription to {}", c),
-            Err(e) => error!("{}", e),
+            Ok(()) => info!("Joined consumer group {} on {}", group, c),
+            Err(e) => panic!("{:?}", e),
         }
     }
 
+    // blocking reads get a connection of their own, so they don't hold up
+    // everything else sharing the multiplexed one
+    let mut reader = RedisBus {
+        connection: None,
+        ..bus.clone()
+    };
+
+    match reader.connect().await
+    {
+        Ok(()) => info!("Redis stream reader connected as {}", consumer),
+        Err(e) => panic!("{}", e),
+    }
+
     let db = Arc::new(db);
     let listing_db = Arc::new(listing_db);
-    while let Some(msg) = ps.on_message().next().await
+    let filters = Arc::new(Filters::new(Duration::from_secs(
+        sprite_settings.filter_reload_secs as u64,
+    )));
+    let consumer = Arc::new(consumer);
+    let in_flight = Arc::new(tokio::sync::Semaphore::new(MAX_IN_FLIGHT));
+    let mut last_claim = Instant::now();
+    loop
     {
-        let db = db.clone();
-        let listing_db = listing_db.clone();
-        let mut bus = bus.clone();
-        let sprite_settings = sprite_settings.clone();
+        /* wait for room for at least one message, then read no more than
+         * there is room for.
+         */
+        let mut permits = in_flight
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the in-flight semaphore is never closed");
+        let free = in_flight.available_permits().min(READ_COUNT - 1);
+        if let Ok(more) = in_flight.clone().try_acquire_many_owned(free as u32)
+        {
+            permits.merge(more);
+        }
+
+        let mut batch = match reader
+            .read_group(
+                channels,
+                &group,
+                &consumer,
+                permits.num_permits(),
+                READ_BLOCK_MS,
+            )
+            .await
+        {
+            Ok(b) => b,
+            Err(e) =>
+            {
+                error!("Could not read from streams: {:?}", e);
+                tokio::time::sleep(Duration::from_millis(
+                    READ_BLOCK_MS as u64,
+                ))
+                .await;
+                continue;
+            }
+        };
 
-        tokio::task::spawn({
-            async move {
-                // Parse the payload
-                let payload = msg.get_payload::<String>().unwrap();
-                match serde_json::from_str::<Message>(&payload)
+        if last_claim.elapsed() >= claim_idle
+            && batch.len() < permits.num_permits()
+        {
+            for c in channels
+            {
+                let room = permits.num_permits().saturating_sub(batch.len());
+                if room == 0
                 {
-                    Ok(m) => match dispatch_message(
-                        &m,
-                        &db,
-                        &listing_db,
-                        &sprite_settings,
-                        &mut bus,
+                    break;
+                }
+                match bus
+                    .autoclaim(
+                        c,
+                        &group,
+                        &consumer,
+                        claim_idle.as_millis() as usize,
+                        room,
                     )
                     .await
+                {
+                    Ok(claimed) =>
                     {
-                        Ok(r) => Ok(r),
-                        Err(e) =>
+                        if !claimed.is_empty()
                         {
-                            error!("Dispatch failed, {:?}", e);
-                            Err(e.to_string())
+                            info!(
+                                "Claimed {} idle messages on {}",
+                                claimed.len(),
+                                c
+                            );
                         }
-                    },
-                    Err(e) =>
-                    {
-                        warn!("Could not deserialize '{}': {}", &payload, e);
-                        Err("no".to_string())
+                        batch.extend(claimed);
                     }
+                    Err(e) => error!("Could not claim idle messages: {:?}", e),
                 }
             }
-        });
-    }
+            last_claim = Instant::now();
+        }
 
-    bus.publish("x", "{\"h\": 3}").await.unwrap();
-    Ok(())
+        for msg in batch
+        {
+            /* the count of a read applies to each stream on its own, so a
+             * read across several can bring back more than there is room
+             * for. the extra messages wait their turn here.
+             */
+            let permit = match permits.split(1)
+            {
+                Some(p) => p,
+                None => in_flight
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("the in-flight semaphore is never closed"),
+            };
+            let db = db.clone();
+            let listing_db = listing_db.clone();
+            let mut bus = bus.clone();
+            let sprite_settings = sprite_settings.clone();
+            let retry_settings = retry_settings.clone();
+            let filters = filters.clone();
+            let group = group.clone();
+            let consumer = consumer.clone();
+
+            tokio::task::spawn({
+                async move {
+                    let _permit = permit;
+                    handle_message(
+                        msg,
+                        &db,
+                        &listing_db,
+                        &sprite_settings,
+                        &retry_settings,
+                        &filters,
+                        &mut bus,
+                        &group,
+                        &consumer,
+                        claim_idle,
+                    )
+                    .await
+                }
+            });
+        }
+    }
 }
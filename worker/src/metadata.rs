@@ -0,0 +1,93 @@
+use image::ImageFormat;
+use img_parts::{
+    jpeg::{
+        markers,
+        Jpeg,
+    },
+    png::Png,
+    riff::{
+        RiffChunk,
+        RiffContent,
+    },
+    webp::{
+        WebP,
+        CHUNK_EXIF,
+        CHUNK_VP8X,
+        CHUNK_XMP,
+    },
+    Bytes,
+};
+
+// PNG chunks that carry text, timestamps or EXIF rather than pixels
+const PNG_METADATA: [[u8; 4]; 5] =
+    [*b"eXIf", *b"tEXt", *b"zTXt", *b"iTXt", *b"tIME"];
+
+// VP8X flags saying the EXIF and XMP chunks are there
+const VP8X_EXIF: u8 = 0b0000_1000;
+const VP8X_XMP: u8 = 0b0000_0100;
+
+/* drop EXIF, XMP, IPTC, comments and the like from a JPEG, PNG or WebP
+ * without touching its pixels. colour profiles, and the segments JPEG
+ * decoders need to get the colours right, are kept. other formats are
+ * returned as they are.
+ */
+pub fn strip_metadata(
+    format: ImageFormat,
+    data: Vec<u8>,
+) -> Result<Vec<u8>, img_parts::Error>
+{
+    let data = Bytes::from(data);
+    let stripped = match format
+    {
+        ImageFormat::Jpeg =>
+        {
+            let mut jpeg = Jpeg::from_bytes(data)?;
+            jpeg.segments_mut().retain(|s| match s.marker()
+            {
+                markers::APP0 | markers::APP14 => true,
+                markers::APP2 => s.contents().starts_with(b"ICC_PROFILE\0"),
+                markers::APP1..=markers::APP15 | markers::COM => false,
+                _ => true,
+            });
+            jpeg.encoder().bytes()
+        }
+        ImageFormat::Png =>
+        {
+            let mut png = Png::from_bytes(data)?;
+            png.chunks_mut()
+                .retain(|c| !PNG_METADATA.contains(&c.kind()));
+            png.encoder().bytes()
+        }
+        ImageFormat::WebP =>
+        {
+            let mut webp = WebP::from_bytes(data)?;
+            webp.remove_chunks_by_id(CHUNK_EXIF);
+            webp.remove_chunks_by_id(CHUNK_XMP);
+
+            // the extended header still claims them otherwise
+            for c in webp.chunks_mut().iter_mut()
+            {
+                if c.id() != CHUNK_VP8X
+                {
+                    continue;
+                }
+                if let Some(header) = c.content().data()
+                {
+                    let mut header = header.to_vec();
+                    if let Some(flags) = header.first_mut()
+                    {
+                        *flags &= !(VP8X_EXIF | VP8X_XMP);
+                    }
+                    *c = RiffChunk::new(
+                        CHUNK_VP8X,
+                        RiffContent::Data(Bytes::from(header)),
+                    );
+                }
+            }
+            webp.encoder().bytes()
+        }
+        _ => data,
+    };
+
+    Ok(stripped.to_vec())
+}